@@ -6,13 +6,74 @@ use apdu_dispatch::iso7816::Status;
 use trussed::{
     syscall,
     Client as TrussedClient,
+    types::consent,
 };
+use crate::attestation::{Attestation, Context};
+use crate::audit::{self, Event};
+use crate::channel::{self, Channel};
+use crate::config::{self, Config, NfcPolicy};
+use crate::enablement::{self, Availability, Enablement, Transport};
+use crate::error::Error;
+use crate::fci;
+use crate::health::HealthTests;
+use crate::keepalive::{Keepalive, KeepaliveStatus};
+use crate::objects::{self, Object};
+use crate::pin::{self, Pin, Session};
+use crate::registry::{self, AppInfo};
+use crate::selftest;
+use crate::storage::{self, StorageInfo};
+use crate::update::{self, Stage, Update};
+use crate::version::{self, VersionInfo};
 
-const UPDATE: VendorCommand = VendorCommand::H51;
-const REBOOT: VendorCommand = VendorCommand::H53;
-const RNG: VendorCommand = VendorCommand::H60;
-const VERSION: VendorCommand = VendorCommand::H61;
-const UUID: VendorCommand = VendorCommand::H62;
+pub(crate) const UPDATE: VendorCommand = VendorCommand::H51;
+pub(crate) const REBOOT: VendorCommand = VendorCommand::H53;
+pub(crate) const MIN_VERSION: VendorCommand = VendorCommand::H54;
+pub(crate) const BOOT_SLOTS: VendorCommand = VendorCommand::H55;
+pub(crate) const CONFIRM: VendorCommand = VendorCommand::H56;
+pub(crate) const REVERT: VendorCommand = VendorCommand::H57;
+pub(crate) const UPDATE_STATUS: VendorCommand = VendorCommand::H58;
+pub(crate) const RESET: VendorCommand = VendorCommand::H59;
+pub(crate) const RNG: VendorCommand = VendorCommand::H60;
+pub(crate) const VERSION: VendorCommand = VendorCommand::H61;
+pub(crate) const UUID: VendorCommand = VendorCommand::H62;
+pub(crate) const RNG_HEALTH: VendorCommand = VendorCommand::H63;
+pub(crate) const ATTEST: VendorCommand = VendorCommand::H64;
+pub(crate) const CONFIG_GET: VendorCommand = VendorCommand::H65;
+pub(crate) const CONFIG_SET: VendorCommand = VendorCommand::H66;
+pub(crate) const CONFIG_LIST: VendorCommand = VendorCommand::H67;
+pub(crate) const PIN: VendorCommand = VendorCommand::H68;
+pub(crate) const SECURE_CHANNEL: VendorCommand = VendorCommand::H69;
+pub(crate) const READ_LOG: VendorCommand = VendorCommand::H6A;
+pub(crate) const LOG_HEAD: VendorCommand = VendorCommand::H6B;
+pub(crate) const SELF_TEST: VendorCommand = VendorCommand::H6C;
+pub(crate) const STORAGE_INFO: VendorCommand = VendorCommand::H6D;
+pub(crate) const LIST_APPS: VendorCommand = VendorCommand::H6E;
+pub(crate) const TRANSPORTS: VendorCommand = VendorCommand::H6F;
+pub(crate) const APP_ENABLEMENT: VendorCommand = VendorCommand::H70;
+
+// Solo management app
+pub(crate) const AID: &[u8] = &[0xA0, 0x00, 0x00, 0x08, 0x47, 0x00, 0x00, 0x00, 0x01];
+
+// Version of the admin protocol, reported on SELECT.
+const PROTOCOL_VERSION: u8 = 0x01;
+
+// Vendor commands supported over APDU, reported on SELECT.
+const INSTRUCTIONS: &[VendorCommand] = &[
+    UPDATE, REBOOT, MIN_VERSION, BOOT_SLOTS, CONFIRM, REVERT, UPDATE_STATUS, RESET,
+    RNG, VERSION, UUID, RNG_HEALTH, ATTEST, CONFIG_GET, CONFIG_SET, CONFIG_LIST,
+    PIN, SECURE_CHANNEL, READ_LOG, LOG_HEAD, SELF_TEST, STORAGE_INFO, LIST_APPS,
+    TRANSPORTS, APP_ENABLEMENT,
+];
+
+// Commands requiring a verified admin PIN, once one is set.
+const PIN_PROTECTED: &[VendorCommand] = &[
+    UPDATE, REBOOT, MIN_VERSION, CONFIRM, REVERT, UUID, CONFIG_SET, TRANSPORTS, APP_ENABLEMENT,
+];
+
+// Trussed returns fewer than 1024 random bytes per call.
+const RANDOM_CHUNK: usize = 512;
+// Eight windows of the adaptive proportion test.
+const HEALTH_SAMPLES: usize = 4096;
 
 pub trait Reboot {
     /// Reboots the device.
@@ -23,6 +84,10 @@ pub trait Reboot {
     /// Presuming the device has a separate mode of operation that
     /// allows updating its firmware (for instance, a bootloader),
     /// reboots the device into this mode.
+    ///
+    /// This is the legacy update path: the host then flashes the image
+    /// through the bootloader, bypassing the signature and rollback
+    /// checks of in-band updates.
     fn reboot_to_firmware_update() -> !;
 
     /// Reboots the device.
@@ -31,79 +96,423 @@ pub trait Reboot {
     /// reliable way of rebooting into the firmware mode of operation,
     /// does so.
     fn reboot_to_firmware_update_destructive() -> !;
+
+    /// Reboots the device.
+    ///
+    /// Installs the image staged by an in-band update, which the admin
+    /// app has verified, and boots it. There is no default implementation:
+    /// this must not fall back to the bootloader of the legacy path.
+    fn reboot_to_staged_update() -> !;
+
+    /// Reboots the device.
+    ///
+    /// Returns the device to factory state: the Trussed storage of
+    /// all clients gets wiped, e.g. by formatting the filesystems
+    /// before or right after rebooting.
+    ///
+    /// The admin app has removed its own files by then. There is no
+    /// default implementation: a platform that merely rebooted would
+    /// strip the admin PIN and audit log, keeping all other storage.
+    fn reboot_to_factory_reset() -> !;
+
+    /// Returns the number of factory resets performed, as counted
+    /// outside of the Trussed storage, e.g. in flash the reset keeps.
+    ///
+    /// As the reset wipes the audit log, the admin app records resets
+    /// performed since it last checked on first use after booting.
+    /// The default implementation is for platforms that don't count them.
+    fn factory_resets() -> u32 {
+        0
+    }
+
+    /// Returns the slot the running firmware was booted from.
+    ///
+    /// Presuming the device keeps two firmware images (A/B slots);
+    /// the default implementation is for devices that don't.
+    fn active_slot() -> Option<Slot> {
+        None
+    }
+
+    /// Returns the slot that will be booted next, if it differs
+    /// from the active slot.
+    fn pending_slot() -> Option<Slot> {
+        None
+    }
+
+    /// Returns whether the running image has been confirmed.
+    ///
+    /// Unconfirmed images are reverted on the next reboot.
+    fn is_confirmed() -> bool {
+        true
+    }
+
+    /// Marks the running image as confirmed.
+    fn confirm() -> Result<(), SlotError> {
+        Err(SlotError)
+    }
+
+    /// Requests booting the previous slot on the next reboot.
+    fn revert() -> Result<(), SlotError> {
+        Err(SlotError)
+    }
+}
+
+/// Firmware slot of devices keeping two firmware images.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+/// The platform failed to perform a slot operation, or does not have slots.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SlotError;
+
+/// Vendor command to execute, with its parameters.
+///
+/// Sub-commands and parameters are sent as P1 and P2 over APDU,
+/// and as leading payload bytes over CTAPHID.
+struct Request<'a> {
+    command: VendorCommand,
+    p1: u8,
+    p2: u8,
+    data: &'a [u8],
+    /// Le, zero if absent.
+    expected: usize,
+}
+
+impl<'a> Request<'a> {
+    /// Request without P2 and Le.
+    fn new(command: VendorCommand, p1: u8, data: &'a [u8]) -> Self {
+        Self { command, p1, p2: 0, data, expected: 0 }
+    }
+
+    /// Parses the payload of a CTAPHID vendor command.
+    fn from_payload(command: VendorCommand, payload: &'a [u8]) -> Result<Self, Error> {
+        match command {
+            // Optional length (u16, big endian)
+            RNG | RNG_HEALTH => match *payload {
+                [] => Ok(Self::new(command, 0, &[])),
+                [p1, p2] => Ok(Self { command, p1, p2, data: &[], expected: 0 }),
+                _ => Err(Error::Malformed),
+            },
+            // Sub-command or setting id first
+            UPDATE | VERSION | CONFIG_GET | CONFIG_SET | PIN | APP_ENABLEMENT => match payload.split_first() {
+                Some((&p1, data)) => Ok(Self::new(command, p1, data)),
+                None => Ok(Self::new(command, 0, &[])),
+            },
+            _ => Ok(Self::new(command, 0, payload)),
+        }
+    }
+}
+
+/// Whether the CTAPHID vendor command `command` with `payload` is one old
+/// hosts send: REBOOT, UUID, VERSION and RNG without sub-command or length,
+/// and UPDATE without sub-command or with REBOOT_DESTRUCTIVE.
+///
+/// These reply as the original firmware did, without status byte.
+pub(crate) fn legacy(command: VendorCommand, payload: &[u8]) -> bool {
+    match command {
+        REBOOT | UUID => true,
+        VERSION => payload.first() != Some(&version::INFO),
+        RNG => payload.is_empty(),
+        UPDATE => matches!(*payload, [] | [update::REBOOT_DESTRUCTIVE]),
+        _ => false,
+    }
+}
+
+fn slot_byte(slot: Option<Slot>) -> u8 {
+    match slot {
+        Some(Slot::A) => 0x00,
+        Some(Slot::B) => 0x01,
+        None => 0xFF,
+    }
 }
 
-pub struct App<T, R>
+pub struct App<T, R, S>
 where T: TrussedClient,
-      R: Reboot,
+      R: Reboot + StorageInfo + Keepalive,
+      S: Stage,
 {
     trussed: T,
     uuid: [u8; 16],
-    version: u32,
+    version: VersionInfo,
+    update: Update<S>,
+    attestation: Option<Attestation>,
+    apps: &'static [AppInfo],
+    config: Option<Config>,
+    enablement: Option<Enablement>,
+    pin: Pin,
+    channel: Option<Channel>,
+    /// Whether the factory resets counted by the platform were recorded since boot.
+    resets_recorded: bool,
     boot_interface: PhantomData<R>,
 }
 
-impl<T, R> App<T, R>
+impl<T, R, S> App<T, R, S>
 where T: TrussedClient,
-      R: Reboot,
+      R: Reboot + StorageInfo + Keepalive,
+      S: Stage,
 {
-    pub fn new(client: T, uuid: [u8; 16], version: u32) -> Self {
-        Self { trussed: client, uuid, version, boot_interface: PhantomData }
+    /// Creates the app, firmware images staged in-band must be signed
+    /// with the Ed25519 key `update_key`.
+    ///
+    /// Without `attestation`, the ATTEST command is not supported.
+    /// The applications installed on the device are listed in `apps`.
+    pub fn new(
+        client: T,
+        uuid: [u8; 16],
+        version: VersionInfo,
+        stage: S,
+        update_key: [u8; 32],
+        attestation: Option<Attestation>,
+        apps: &'static [AppInfo],
+    ) -> Self {
+        Self {
+            trussed: client,
+            uuid,
+            version,
+            update: Update::new(stage, update_key),
+            attestation,
+            apps,
+            config: None,
+            enablement: None,
+            pin: Pin::default(),
+            channel: None,
+            resets_recorded: false,
+            boot_interface: PhantomData,
+        }
+    }
+
+    /// Returns the configuration, loading it on first use.
+    fn config(&mut self) -> &mut Config {
+        let trussed = &mut self.trussed;
+        self.config.get_or_insert_with(|| Config::load(trussed))
     }
 
-    fn user_present(&mut self) -> bool {
-        let user_present = syscall!(self.trussed.confirm_user_present(15_000)).result;
-        user_present.is_ok()
+    /// Returns the enabled applications and transports, loading them on first use.
+    fn enablement(&mut self) -> &mut Enablement {
+        let trussed = &mut self.trussed;
+        self.enablement.get_or_insert_with(|| Enablement::load(trussed))
+    }
+
+    /// Sets and persists the bitmap of enabled transports, effective after reboot.
+    fn set_transports(&mut self, transports: u8) -> Result<(), enablement::Error> {
+        let mut enablement = *self.enablement();
+        enablement.set_transports(&mut self.trussed, transports)?;
+        self.enablement = Some(enablement);
+        self.log(Event::TransportsChanged, transports.into());
+        Ok(())
+    }
+
+    /// Enables or disables the application with AID `aid`, effective after reboot.
+    fn set_app(&mut self, enabled: bool, aid: &[u8]) -> Result<(), enablement::Error> {
+        let mut enablement = *self.enablement();
+        enablement.set_app(&mut self.trussed, aid, enabled, AID)?;
+        self.enablement = Some(enablement);
+        self.log(Event::AppChanged, enabled.into());
+        Ok(())
+    }
+
+    /// Waits for the user to confirm presence, up to the configured timeout.
+    ///
+    /// The wait is a single Trussed request, kept alive and interrupted
+    /// on cancel by the platform, see `Keepalive`.
+    fn user_present(&mut self) -> Result<(), Error> {
+        if R::cancelled() {
+            return Err(Error::Cancelled);
+        }
+        R::keepalive(KeepaliveStatus::UserPresenceNeeded);
+        let timeout = self.config().user_presence_timeout;
+        match syscall!(self.trussed.confirm_user_present(timeout)).result {
+            Ok(()) => {
+                R::keepalive(KeepaliveStatus::Processing);
+                Ok(())
+            }
+            Err(consent::Error::TimedOut) => Err(Error::Timeout),
+            Err(consent::Error::Interrupted) => Err(Error::Cancelled),
+            Err(_) => Err(Error::Denied),
+        }
+    }
+
+    /// Whether `command` may be executed in `session`, as per the admin PIN policy.
+    fn authorized(&mut self, command: VendorCommand, session: Session) -> bool {
+        !PIN_PROTECTED.contains(&command) || self.pin.authorized(&mut self.trussed, session)
+    }
+
+    /// Sets and persists a setting, payload is its encoded value.
+    fn set_config(&mut self, id: u8, value: &[u8]) -> Result<(), config::Error> {
+        let mut config = *self.config();
+        config.set(id, value)?;
+        config.store(&mut self.trussed)?;
+        self.config = Some(config);
+        self.log(Event::ConfigChanged, id.into());
+        Ok(())
+    }
+
+    /// Records an admin operation in the audit log.
+    fn log(&mut self, event: Event, detail: u32) {
+        self.record_resets();
+        audit::append(&mut self.trussed, event, detail).ok();
+    }
+
+    /// Records the factory resets counted by the platform, once per boot.
+    fn record_resets(&mut self) {
+        if !self.resets_recorded {
+            self.resets_recorded = true;
+            audit::record_resets(&mut self.trussed, R::factory_resets()).ok();
+        }
+    }
+
+    fn raise_min_version(&mut self, data: &[u8]) -> Result<u32, update::Error> {
+        let version = update::raise_min_version(&mut self.trussed, self.version.number, data)?;
+        self.log(Event::MinVersionRaised, version);
+        Ok(version)
+    }
+
+    fn change_pin(&mut self, session: Session, data: &[u8]) -> Result<(), pin::Error> {
+        let result = self.pin.change(&mut self.trussed, session, data);
+        self.log_pin(result)?;
+        self.log(Event::PinChanged, 0);
+        Ok(())
+    }
+
+    fn verify_pin(&mut self, session: Session, data: &[u8]) -> Result<(), pin::Error> {
+        let result = self.pin.verify(&mut self.trussed, session, data);
+        self.log_pin(result)
+    }
+
+    /// Records failed PIN verifications.
+    fn log_pin(&mut self, result: Result<(), pin::Error>) -> Result<(), pin::Error> {
+        match result {
+            Err(pin::Error::Wrong(retries)) => self.log(Event::PinFailed, retries.into()),
+            Err(pin::Error::Blocked) => self.log(Event::PinFailed, 0),
+            _ => {}
+        }
+        result
+    }
+
+    /// Passes the log head and the next sequence number, followed by the
+    /// attestation signature over the label `admin-app log head` (NUL
+    /// terminated) and both if there is an attestation key, to `sink`.
+    fn log_head(&mut self, mut sink: impl FnMut(&[u8])) -> Result<(), Error> {
+        let head = audit::head(&mut self.trussed);
+        sink(&head);
+        if let Some(attestation) = self.attestation {
+            sink(&attestation.sign(&mut self.trussed, Context::LogHead, &head)?);
+        }
+        Ok(())
+    }
+
+    /// Removes the files of the admin app: PIN, configuration, enablement
+    /// and audit log. The minimum firmware version is kept, as it guards
+    /// against rollback.
+    fn wipe(&mut self) {
+        self.pin.remove(&mut self.trussed);
+        Config::remove(&mut self.trussed);
+        self.config = None;
+        Enablement::remove(&mut self.trussed);
+        self.enablement = None;
+        audit::remove(&mut self.trussed);
+    }
+
+    /// Passes `count` random bytes to `sink`, in as many Trussed calls as needed.
+    fn random_bytes(&mut self, mut count: usize, mut sink: impl FnMut(&[u8])) {
+        while count > 0 {
+            let chunk = count.min(RANDOM_CHUNK);
+            sink(&syscall!(self.trussed.random_bytes(chunk)).bytes);
+            count -= chunk;
+        }
+    }
+
+    /// Appends the CBOR encoded version information to `sink`.
+    fn version_info(&self, sink: impl FnOnce(&[u8])) -> Result<(), Error> {
+        let mut buffer = [0u8; version::INFO_SIZE];
+        let encoded = trussed::cbor_serialize(&self.version, &mut buffer).map_err(|_| Error::Failed)?;
+        sink(encoded);
+        Ok(())
+    }
+
+    /// Attests the device identity, see `Attestation::attest`.
+    fn attest(&mut self, nonce: &[u8], sink: impl FnMut(&[u8])) -> Result<(), Error> {
+        let attestation = self.attestation.ok_or(Error::Unsupported)?;
+        attestation.attest(&mut self.trussed, nonce, &self.uuid, self.version.number, sink)?;
+        Ok(())
+    }
+
+    /// Runs the RNG health tests over `count` random bytes.
+    fn rng_health(&mut self, count: usize) -> [u8; 25] {
+        let mut tests = HealthTests::default();
+        self.random_bytes(count, |bytes| tests.feed(bytes));
+        tests.report()
+    }
+
+    /// Active slot, pending slot and whether the running image is confirmed.
+    fn boot_slots() -> [u8; 3] {
+        [slot_byte(R::active_slot()), slot_byte(R::pending_slot()), R::is_confirmed() as u8]
     }
 
 
 }
 
-impl<T, R> hid::App for App<T, R>
+impl<T, R, S> hid::App for App<T, R, S>
 where T: TrussedClient,
-      R: Reboot
+      R: Reboot + StorageInfo + Keepalive,
+      S: Stage,
 {
     fn commands(&self) -> &'static [HidCommand] {
         &[
             HidCommand::Wink,
             HidCommand::Vendor(UPDATE),
             HidCommand::Vendor(REBOOT),
+            HidCommand::Vendor(MIN_VERSION),
+            HidCommand::Vendor(BOOT_SLOTS),
+            HidCommand::Vendor(CONFIRM),
+            HidCommand::Vendor(REVERT),
+            HidCommand::Vendor(UPDATE_STATUS),
+            HidCommand::Vendor(RESET),
             HidCommand::Vendor(RNG),
             HidCommand::Vendor(VERSION),
             HidCommand::Vendor(UUID),
+            HidCommand::Vendor(RNG_HEALTH),
+            HidCommand::Vendor(ATTEST),
+            HidCommand::Vendor(CONFIG_GET),
+            HidCommand::Vendor(CONFIG_SET),
+            HidCommand::Vendor(CONFIG_LIST),
+            HidCommand::Vendor(PIN),
+            HidCommand::Vendor(READ_LOG),
+            HidCommand::Vendor(LOG_HEAD),
+            HidCommand::Vendor(SELF_TEST),
+            HidCommand::Vendor(STORAGE_INFO),
+            HidCommand::Vendor(LIST_APPS),
+            HidCommand::Vendor(TRANSPORTS),
+            HidCommand::Vendor(APP_ENABLEMENT),
         ]
     }
 
     fn call(&mut self, command: HidCommand, input_data: &Message, response: &mut Message) -> hid::AppResult {
         match command {
-            HidCommand::Vendor(REBOOT) => R::reboot(),
-            HidCommand::Vendor(RNG) => {
-                // Fill the HID packet (57 bytes)
-                response.extend_from_slice(
-                    &syscall!(self.trussed.random_bytes(57)).bytes.as_slice()
-                ).ok();
-            }
-            HidCommand::Vendor(UPDATE) => {
-                if self.user_present() {
-                    if input_data.len() > 0 && input_data[0] == 0x01 {
-                        R::reboot_to_firmware_update_destructive();
-                    } else {
-                        R::reboot_to_firmware_update();
-                    }
-                } else {
-                    return Err(hid::Error::InvalidLength);
-                }
+            HidCommand::Vendor(vendor_command) if legacy(vendor_command, input_data) => {
+                // Response data only, errors as CTAPHID errors
+                let request = Request::from_payload(vendor_command, input_data)?;
+                let room = response.capacity() - response.len();
+                self.execute(None, request, room, |bytes| { response.extend_from_slice(bytes).ok(); })?;
             }
-            HidCommand::Vendor(UUID) => {
-                // Get UUID
-                response.extend_from_slice(&self.uuid).ok();
-            }
-            HidCommand::Vendor(VERSION) => {
-                // GET VERSION
-                response.extend_from_slice(&self.version.to_be_bytes()).ok();
+            HidCommand::Vendor(vendor_command) => {
+                // Status byte, followed by the response data
+                response.extend_from_slice(&[0x00]).ok();
+                let room = response.capacity() - response.len();
+                let result = Request::from_payload(vendor_command, input_data).and_then(|request| {
+                    self.execute(None, request, room, |bytes| { response.extend_from_slice(bytes).ok(); })
+                });
+                if let Err(error) = result {
+                    response.clear();
+                    error.encode(|bytes| { response.extend_from_slice(bytes).ok(); });
+                }
             }
             HidCommand::Wink => {
-                syscall!(self.trussed.wink(core::time::Duration::from_secs(10)));
+                let duration = self.config().wink_duration;
+                syscall!(self.trussed.wink(core::time::Duration::from_millis(duration.into())));
             }
             _ => {
                 return Err(hid::Error::InvalidCommand);
@@ -113,64 +522,428 @@ where T: TrussedClient,
     }
 }
 
-impl<T, R> iso7816::App for App<T, R>
+impl<T, R, S> Availability for App<T, R, S>
+where T: TrussedClient,
+      R: Reboot + StorageInfo + Keepalive,
+      S: Stage,
+{
+    fn transport_enabled(&mut self, transport: Transport) -> bool {
+        self.enablement().transport_enabled(transport)
+    }
+
+    fn app_enabled(&mut self, aid: &[u8]) -> bool {
+        self.enablement().app_enabled(aid)
+    }
+}
+
+impl<T, R, S> iso7816::App for App<T, R, S>
 where T: TrussedClient,
-      R: Reboot
+      R: Reboot + StorageInfo + Keepalive,
+      S: Stage,
 {
     // Solo management app
     fn aid(&self) -> iso7816::Aid {
-        iso7816::Aid::new(&[ 0xA0, 0x00, 0x00, 0x08, 0x47, 0x00, 0x00, 0x00, 0x01])
+        iso7816::Aid::new(AID)
     }
 }
 
-impl<T, R> apdu::App<{command::SIZE}, {response::SIZE}> for App<T, R>
+impl<T, R, S> apdu::App<{command::SIZE}, {response::SIZE}> for App<T, R, S>
 where T: TrussedClient,
-      R: Reboot
+      R: Reboot + StorageInfo + Keepalive,
+      S: Stage,
 {
 
-    fn select(&mut self, _apdu: &Command, _reply: &mut response::Data) -> apdu::Result {
+    fn select(&mut self, _apdu: &Command, reply: &mut response::Data) -> apdu::Result {
+        self.pin.logout(Session::Apdu);
+        self.close_channel();
+
+        // Reply with the FCI template. The UUID is omitted if it may not be
+        // read without PIN, or over NFC, as the interface is not known here.
+        let uuid = (self.authorized(UUID, Session::Apdu) && self.config().nfc_policy == NfcPolicy::Restricted).then_some(self.uuid);
+        let mut instructions = [0u8; objects::INSTRUCTIONS.len() + INSTRUCTIONS.len()];
+        instructions[..objects::INSTRUCTIONS.len()].copy_from_slice(&objects::INSTRUCTIONS);
+        let mut count = objects::INSTRUCTIONS.len();
+        for &command in INSTRUCTIONS {
+            if command != ATTEST || self.attestation.is_some() {
+                instructions[count] = command.into();
+                count += 1;
+            }
+        }
+        fci::encode(AID, PROTOCOL_VERSION, self.version.number, uuid.as_ref(), &instructions[..count], |bytes| {
+            reply.extend_from_slice(bytes).ok();
+        });
         Ok(())
     }
 
-    fn deselect(&mut self) {}
+    fn deselect(&mut self) {
+        self.pin.logout(Session::Apdu);
+        self.close_channel();
+    }
 
+    // Chained commands arrive reassembled, and replies longer than Le are
+    // continued with 61xx and GET RESPONSE, by apdu-dispatch.
     fn call(&mut self, interface: apdu::Interface, apdu: &Command, reply: &mut response::Data) -> apdu::Result {
         let instruction: u8 = apdu.instruction().into();
+        let class = apdu.class().into_inner();
 
-        let command: VendorCommand = instruction.try_into().map_err(|_e| Status::InstructionNotSupportedOrInvalid)?;
+        // Not even a secure channel may be opened when NFC is disabled
+        if interface == apdu::Interface::Contactless && self.config().nfc_policy == NfcPolicy::Disabled {
+            return Err(Error::Denied.into());
+        }
 
-        match command {
-            REBOOT => R::reboot(),
+        if matches!(VendorCommand::try_from(instruction), Ok(SECURE_CHANNEL)) && self.vendor_class(class) {
+            self.close_channel();
+            let attestation = self.attestation;
+            let channel = Channel::open(&mut self.trussed, apdu.data(), attestation.as_ref(), |bytes| {
+                reply.extend_from_slice(bytes).ok();
+            }).map_err(Error::from)?;
+            self.channel = Some(channel);
+            return Ok(());
+        }
+
+        if self.channel.is_none() {
+            if interface == apdu::Interface::Contactless && self.config().nfc_policy == NfcPolicy::Secure {
+                return Err(Error::Unauthorized.into());
+            }
+            let limit = reply.capacity();
+            return self.dispatch(interface, apdu, limit, reply);
+        }
+
+        // Secure messaging: unwrap the command, wrap the response
+        let header = [instruction, apdu.p1, apdu.p2];
+        let data = self.with_channel(|channel, trussed| channel.unwrap(trussed, header, apdu.data()))?;
+        let command = channel::command(class, header, &data).map_err(Error::from)?;
+        // Replies are limited to what can be wrapped
+        self.dispatch(interface, &command, channel::MAX_DATA, reply)?;
+        let wrapped = self.with_channel(|channel, trussed| channel.wrap(trussed, &reply[..]))?;
+        reply.clear();
+        reply.extend_from_slice(&wrapped).ok();
+        Ok(())
+    }
+}
+
+impl<T, R, S> App<T, R, S>
+where T: TrussedClient,
+      R: Reboot + StorageInfo + Keepalive,
+      S: Stage,
+{
+    fn close_channel(&mut self) {
+        if let Some(channel) = self.channel.take() {
+            channel.close(&mut self.trussed);
+        }
+    }
+
+    /// Runs `f` on the open secure channel, closing it on failure.
+    fn with_channel<V>(&mut self, f: impl FnOnce(&mut Channel, &mut T) -> Result<V, channel::Error>) -> Result<V, Error> {
+        let channel = self.channel.as_mut().ok_or(Error::Unauthorized)?;
+        let result = f(channel, &mut self.trussed);
+        if result.is_err() {
+            self.close_channel();
+        }
+        result.map_err(Error::from)
+    }
+
+    /// Whether vendor commands may be sent as instructions of `class`.
+    fn vendor_class(&mut self, class: u8) -> bool {
+        class & objects::PROPRIETARY_CLASS != 0 || self.config().legacy_instructions
+    }
+
+    /// Executes a plaintext APDU command, whose response data may take up to `limit` bytes.
+    fn dispatch<const C: usize>(
+        &mut self,
+        interface: apdu::Interface,
+        apdu: &iso7816::Command<C>,
+        limit: usize,
+        reply: &mut response::Data,
+    ) -> apdu::Result {
+        let instruction: u8 = apdu.instruction().into();
+
+        let request = match instruction {
+            objects::VERIFY if apdu.p2 != objects::PIN_REFERENCE || !matches!(apdu.p1, 0x00 | objects::RESET_VERIFICATION) => {
+                return Err(Status::IncorrectP1OrP2Parameter);
+            }
+            objects::VERIFY if apdu.p1 == objects::RESET_VERIFICATION || apdu.data().is_empty() => {
+                return self.verification_status(apdu.p1, apdu.data());
+            }
+            objects::VERIFY | objects::GET_DATA | objects::PUT_DATA => {
+                Self::translate(instruction, apdu.p1, apdu.p2, apdu.data())?
+            }
+            _ => {
+                if !self.vendor_class(apdu.class().into_inner()) {
+                    return Err(Status::InstructionNotSupportedOrInvalid);
+                }
+                let command: VendorCommand = instruction.try_into().map_err(|_e| Status::InstructionNotSupportedOrInvalid)?;
+                Request { command, p1: apdu.p1, p2: apdu.p2, data: apdu.data(), expected: apdu.expected() }
+            }
+        };
+
+        let room = limit.min(reply.capacity()).saturating_sub(reply.len());
+        self.execute(Some(interface), request, room, |bytes| { reply.extend_from_slice(bytes).ok(); })
+            .map_err(Status::from)
+    }
+
+    /// Answers VERIFY without PIN: P1 = FF ends the APDU session, otherwise
+    /// the verification status is reported without counting down the retries.
+    fn verification_status(&mut self, p1: u8, data: &[u8]) -> apdu::Result {
+        if !data.is_empty() {
+            return Err(Status::WrongLength);
+        }
+        if p1 == objects::RESET_VERIFICATION {
+            self.pin.logout(Session::Apdu);
+            return Ok(());
+        }
+        // PIN set, retries left and verified
+        match self.pin.status(&mut self.trussed, Session::Apdu) {
+            [0, _, _] | [_, _, 1] => Ok(()),
+            [_, 0, _] => Err(Status::OperationBlocked),
+            [_, retries, _] => Err(Status::RemainingRetries(retries)),
+        }
+    }
+
+    /// Translates VERIFY, GET DATA and PUT DATA into the equivalent vendor command.
+    fn translate(instruction: u8, p1: u8, p2: u8, data: &[u8]) -> Result<Request<'_>, Error> {
+        if instruction == objects::VERIFY {
+            return Ok(Request::new(PIN, pin::VERIFY, data));
+        }
+        let object = Object::from_tag(p1, p2).ok_or(Error::Unsupported)?;
+        if instruction == objects::PUT_DATA {
+            if data.is_empty() {
+                return Err(Error::Malformed);
+            }
+            return match object {
+                Object::MinVersion => Ok(Request::new(MIN_VERSION, 0, data)),
+                Object::Transports => Ok(Request::new(TRANSPORTS, 0, data)),
+                Object::Config(id) => Ok(Request::new(CONFIG_SET, id, data)),
+                _ => Err(Error::Unsupported),
+            };
+        }
+        Ok(match object {
+            Object::Uuid => Request::new(UUID, 0, &[]),
+            Object::Version => Request::new(VERSION, 0, &[]),
+            Object::VersionInfo => Request::new(VERSION, version::INFO, &[]),
+            Object::MinVersion => Request::new(MIN_VERSION, 0, &[]),
+            Object::UpdateStatus => Request::new(UPDATE_STATUS, 0, &[]),
+            Object::BootSlots => Request::new(BOOT_SLOTS, 0, &[]),
+            Object::StorageInfo => Request::new(STORAGE_INFO, 0, &[]),
+            Object::Apps => Request::new(LIST_APPS, 0, &[]),
+            Object::Transports => Request::new(TRANSPORTS, 0, &[]),
+            Object::DisabledApps => Request::new(APP_ENABLEMENT, 0, &[]),
+            Object::PinStatus => Request::new(PIN, pin::STATUS, &[]),
+            Object::LogHead => Request::new(LOG_HEAD, 0, &[]),
+            Object::Config(id) => Request::new(CONFIG_GET, id, &[]),
+        })
+    }
+
+    /// Executes a vendor command received over `interface`, or over CTAPHID if none,
+    /// passing the response data to `reply`, which has `room` bytes left.
+    fn execute(
+        &mut self,
+        interface: Option<apdu::Interface>,
+        request: Request<'_>,
+        room: usize,
+        mut reply: impl FnMut(&[u8]),
+    ) -> Result<(), Error> {
+        let contactless = interface == Some(apdu::Interface::Contactless);
+        let session = match interface {
+            None => Session::Ctaphid,
+            Some(_) => Session::Apdu,
+        };
+        if !self.authorized(request.command, session) {
+            return Err(Error::Unauthorized);
+        }
+
+        match request.command {
+            REBOOT => {
+                self.log(Event::Reboot, 0);
+                R::reboot();
+            }
+            RESET => {
+                // Factory reset not over NFC
+                if contactless {
+                    return Err(Error::Denied);
+                }
+                self.user_present()?;
+                self.wipe();
+                self.log(Event::Reset, 0);
+                R::reboot_to_factory_reset();
+            }
             RNG => {
-                // Random bytes
-                reply.extend_from_slice(&syscall!(self.trussed.random_bytes(57)).bytes.as_slice()).ok();
+                // Random bytes, as many as P1-P2 requests, or else up to Le, or else 57 (fills a HID packet)
+                let count = match u16::from_be_bytes([request.p1, request.p2]) as usize {
+                    0 if request.expected > 0 => request.expected.min(room),
+                    0 => 57,
+                    count if count <= room => count,
+                    _ => return Err(Error::Malformed),
+                };
+                self.random_bytes(count, reply);
+            }
+            RNG_HEALTH => {
+                // Test as many bytes as P1-P2 requests, or else 4096
+                let count = match u16::from_be_bytes([request.p1, request.p2]) as usize {
+                    0 => HEALTH_SAMPLES,
+                    count => count,
+                };
+                reply(&self.rng_health(count));
             }
             UPDATE => {
-                // Boot to mcuboot (only when contact interface)
-                if interface == apdu::Interface::Contact && self.user_present()
-                {
-                    if apdu.p1 == 0x01 {
-                        R::reboot_to_firmware_update_destructive();
-                    } else {
+                // Firmware updates not over NFC
+                if contactless {
+                    return Err(Error::Denied);
+                }
+                match request.p1 {
+                    update::BEGIN => {
+                        self.user_present()?;
+                        self.update.begin(&mut self.trussed, self.version.number, request.data)?;
+                        self.log(Event::UpdateStarted, 0);
+                    }
+                    update::WRITE => {
+                        let offset = self.update.write(request.data)?;
+                        reply(&offset.to_be_bytes());
+                    }
+                    update::FINALIZE => {
+                        self.update.finalize(&mut self.trussed, request.data)?;
+                        self.log(Event::UpdateStaged, 0);
+                        R::reboot_to_staged_update();
+                    }
+                    0x00 | update::BOOTLOADER => {
+                        // Boot to mcuboot
+                        self.user_present()?;
+                        self.log(Event::UpdateRequested, 0);
                         R::reboot_to_firmware_update();
                     }
+                    update::REBOOT_DESTRUCTIVE | update::BOOTLOADER_DESTRUCTIVE => {
+                        self.user_present()?;
+                        self.log(Event::UpdateRequested, 1);
+                        R::reboot_to_firmware_update_destructive();
+                    }
+                    _ => return Err(Error::Unsupported),
+                }
+            }
+            MIN_VERSION => {
+                // Get or raise (not over NFC) the minimum firmware version
+                let version = if request.data.is_empty() {
+                    update::min_version(&mut self.trussed)
+                } else if contactless {
+                    return Err(Error::Denied);
+                } else {
+                    self.user_present()?;
+                    self.raise_min_version(request.data)?
+                };
+                reply(&version.to_be_bytes());
+            }
+            UPDATE_STATUS => {
+                reply(&self.update.status());
+            }
+            BOOT_SLOTS => {
+                reply(&Self::boot_slots());
+            }
+            CONFIRM => {
+                R::confirm().map_err(|_| Error::Unsupported)?;
+                self.log(Event::Confirmed, 0);
+            }
+            REVERT => {
+                // Revert not over NFC
+                if contactless {
+                    return Err(Error::Denied);
+                }
+                self.user_present()?;
+                R::revert().map_err(|_| Error::Unsupported)?;
+                self.log(Event::Reverted, 0);
+                R::reboot();
+            }
+            ATTEST => {
+                self.attest(request.data, reply)?;
+            }
+            CONFIG_GET => {
+                self.config().get(request.p1, reply)?;
+            }
+            CONFIG_SET => {
+                self.set_config(request.p1, request.data)?;
+            }
+            CONFIG_LIST => {
+                self.config().list(reply);
+            }
+            PIN => {
+                match request.p1 {
+                    pin::SET => {
+                        self.user_present()?;
+                        self.pin.set(&mut self.trussed, session, request.data)?;
+                        self.log(Event::PinChanged, 0);
+                    }
+                    pin::CHANGE => self.change_pin(session, request.data)?,
+                    pin::VERIFY => self.verify_pin(session, request.data)?,
+                    pin::STATUS => {
+                        reply(&self.pin.status(&mut self.trussed, session));
+                    }
+                    pin::LOGOUT => self.pin.logout(session),
+                    _ => return Err(Error::Unsupported),
+                }
+            }
+            READ_LOG => {
+                self.record_resets();
+                audit::read(&mut self.trussed, request.data, reply).map_err(|_| Error::Malformed)?;
+            }
+            LOG_HEAD => {
+                self.record_resets();
+                self.log_head(reply)?;
+            }
+            SELF_TEST => {
+                reply(&selftest::run(&mut self.trussed));
+            }
+            STORAGE_INFO => {
+                storage::report::<R>(reply);
+            }
+            LIST_APPS => {
+                registry::list(self.apps, reply);
+            }
+            TRANSPORTS => {
+                // Get or set (not over NFC) the bitmap of enabled transports
+                match *request.data {
+                    [] => {
+                        let transports = self.enablement().transports();
+                        reply(&[transports]);
+                    }
+                    [transports] => {
+                        if contactless {
+                            return Err(Error::Denied);
+                        }
+                        self.user_present()?;
+                        self.set_transports(transports)?;
+                    }
+                    _ => return Err(Error::Malformed),
+                }
+            }
+            APP_ENABLEMENT => {
+                // List the disabled applications, or enable (P1 = 1) or disable (P1 = 0)
+                // the one with AID in data (not over NFC)
+                if request.data.is_empty() {
+                    self.enablement().list(reply);
+                } else if request.p1 > 1 {
+                    return Err(Error::Invalid);
+                } else if contactless {
+                    return Err(Error::Denied);
+                } else {
+                    self.user_present()?;
+                    self.set_app(request.p1 == 1, request.data)?;
                 }
-                return Err(Status::ConditionsOfUseNotSatisfied);
             }
             UUID => {
                 // Get UUID
-                reply.extend_from_slice(&self.uuid).ok();
+                reply(&self.uuid);
             }
             VERSION => {
                 // Get version
-                reply.extend_from_slice(&self.version.to_be_bytes()[..]).ok();
+                if request.p1 == version::INFO {
+                    self.version_info(reply)?;
+                } else {
+                    reply(&self.version.number.to_be_bytes());
+                }
             }
 
-            _ => return Err(Status::InstructionNotSupportedOrInvalid),
+            _ => return Err(Error::Unsupported),
 
         }
         Ok(())
 
     }
 }
-
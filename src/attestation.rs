@@ -0,0 +1,107 @@
+//! Device attestation.
+//!
+//! Proves the device is genuine by signing a host challenge, together
+//! with the device UUID and firmware version, with an attestation key
+//! held in Trussed.
+//!
+//! The attestation key also signs the secure channel keys and the audit
+//! log head; each signed message is prefixed with a label naming its
+//! purpose, so that a signature cannot be passed off as another.
+use trussed::{
+    try_syscall,
+    Client as TrussedClient,
+    types::{CertId, KeyId, Mechanism, Message, Signature, SignatureSerialization},
+};
+
+// Longest accepted host nonce.
+const MAX_NONCE: usize = 64;
+
+/// Attestation key and certificate, provisioned into Trussed.
+#[derive(Clone, Copy, Debug)]
+pub struct Attestation {
+    /// P-256 attestation key.
+    pub key: KeyId,
+    /// DER encoded certificate for the attestation key.
+    pub certificate: CertId,
+}
+
+/// Purpose of a signature made with the attestation key.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Context {
+    Attest,
+    Channel,
+    LogHead,
+}
+
+impl Context {
+    /// Label prefixed to the signed message, none is a prefix of another.
+    fn label(self) -> &'static [u8] {
+        match self {
+            Context::Attest => b"admin-app attest\0",
+            Context::Channel => b"admin-app channel\0",
+            Context::LogHead => b"admin-app log head\0",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Error {
+    /// The nonce is empty or too long.
+    Nonce,
+    /// Trussed failed to sign or to read the certificate.
+    Trussed,
+}
+
+impl Attestation {
+    /// Signs the label `admin-app attest` (NUL terminated) followed by
+    /// `nonce || uuid || version` (version as u32, big endian) and passes
+    /// the encoded attestation to `sink`: the DER signature length
+    /// (u16, big endian), the signature and the DER certificate.
+    pub(crate) fn attest<T: TrussedClient>(
+        &self,
+        trussed: &mut T,
+        nonce: &[u8],
+        uuid: &[u8; 16],
+        version: u32,
+        mut sink: impl FnMut(&[u8]),
+    ) -> Result<(), Error> {
+        if nonce.is_empty() || nonce.len() > MAX_NONCE {
+            return Err(Error::Nonce);
+        }
+        let mut message = [0u8; MAX_NONCE + 20];
+        let length = nonce.len() + 20;
+        message[..nonce.len()].copy_from_slice(nonce);
+        message[nonce.len()..length - 4].copy_from_slice(uuid);
+        message[length - 4..length].copy_from_slice(&version.to_be_bytes());
+
+        let signature = self.sign(trussed, Context::Attest, &message[..length])?;
+        let certificate = try_syscall!(trussed.read_certificate(self.certificate))
+            .map_err(|_| Error::Trussed)?
+            .der;
+
+        sink(&(signature.len() as u16).to_be_bytes());
+        sink(&signature);
+        sink(&certificate);
+        Ok(())
+    }
+
+    /// Signs the label of `context` followed by `message` with the attestation
+    /// key, returning the DER signature.
+    pub(crate) fn sign<T: TrussedClient>(&self, trussed: &mut T, context: Context, message: &[u8]) -> Result<Signature, Error> {
+        let mut labelled = Message::new();
+        labelled.extend_from_slice(context.label()).map_err(|_| Error::Trussed)?;
+        labelled.extend_from_slice(message).map_err(|_| Error::Trussed)?;
+        try_syscall!(trussed.sign(Mechanism::P256, self.key, &labelled, SignatureSerialization::Asn1Der))
+            .map(|reply| reply.signature)
+            .map_err(|_| Error::Trussed)
+    }
+}
+
+impl From<Error> for crate::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Nonce => crate::Error::Malformed,
+            Error::Trussed => crate::Error::Failed,
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Tamper-evident audit log of admin operations.
+//!
+//! The log keeps the latest entries, each chained to its predecessors by
+//! `head = SHA-256(head || entry)`. Along with the entries, the hash preceding
+//! the oldest entry kept (the base) is stored, so the chain can be verified
+//! after old entries were dropped.
+//!
+//! Entries are nine bytes: sequence number (u32, big endian), event and
+//! event detail (u32, big endian).
+use core::convert::TryInto;
+use sha2::{Digest, Sha256};
+use trussed::{
+    try_syscall,
+    Client as TrussedClient,
+    types::{Location, Message, PathBuf},
+};
+
+const LOG_PATH: &str = "audit-log";
+// Factory resets recorded, as counted by the platform.
+const RESETS_PATH: &str = "audit-resets";
+const CAPACITY: usize = 64;
+const ENTRY: usize = 9;
+// Entries per READ_LOG reply.
+const PAGE: usize = 16;
+
+/// Logged admin operations, with their detail.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub(crate) enum Event {
+    /// Reboot requested.
+    Reboot = 0x01,
+    /// Reboot into the bootloader requested, detail is whether destructive.
+    UpdateRequested = 0x02,
+    /// In-band upload started.
+    UpdateStarted = 0x03,
+    /// In-band upload staged, about to reboot into it.
+    UpdateStaged = 0x04,
+    /// Minimum firmware version raised, detail is the version.
+    MinVersionRaised = 0x05,
+    /// Running image confirmed.
+    Confirmed = 0x06,
+    /// Revert to the previous slot requested.
+    Reverted = 0x07,
+    /// Factory reset, detail is zero if recorded before the platform reset,
+    /// or else the number of resets the platform counted.
+    Reset = 0x08,
+    /// Setting changed, detail is its id.
+    ConfigChanged = 0x09,
+    /// Admin PIN set or changed.
+    PinChanged = 0x0A,
+    /// Wrong admin PIN, detail is the number of retries left.
+    PinFailed = 0x0B,
+    /// Enabled transports changed, detail is their bitmap.
+    TransportsChanged = 0x0C,
+    /// Application enabled or disabled, detail is whether enabled.
+    AppChanged = 0x0D,
+}
+
+struct Log {
+    base: [u8; 32],
+    entries: [[u8; ENTRY]; CAPACITY],
+    len: usize,
+}
+
+impl Log {
+    fn load<T: TrussedClient>(trussed: &mut T) -> Self {
+        let mut log = Self { base: [0; 32], entries: [[0; ENTRY]; CAPACITY], len: 0 };
+        if let Ok(reply) = try_syscall!(trussed.read_file(Location::Internal, PathBuf::from(LOG_PATH))) {
+            if reply.data.len() >= 32 {
+                log.base.copy_from_slice(&reply.data[..32]);
+                for entry in reply.data[32..].chunks_exact(ENTRY).take(CAPACITY) {
+                    log.entries[log.len].copy_from_slice(entry);
+                    log.len += 1;
+                }
+            }
+        }
+        log
+    }
+
+    fn store<T: TrussedClient>(&self, trussed: &mut T) -> Result<(), ()> {
+        let mut data = Message::new();
+        data.extend_from_slice(&self.base)?;
+        for entry in self.entries() {
+            data.extend_from_slice(entry)?;
+        }
+        try_syscall!(trussed.write_file(Location::Internal, PathBuf::from(LOG_PATH), data, None))
+            .map(drop)
+            .map_err(drop)
+    }
+
+    fn entries(&self) -> &[[u8; ENTRY]] {
+        &self.entries[..self.len]
+    }
+
+    /// Sequence number of the next entry.
+    fn next_sequence(&self) -> u32 {
+        match self.entries().last() {
+            Some(entry) => sequence(entry).wrapping_add(1),
+            None => 0,
+        }
+    }
+
+    /// Hash after the first `count` entries.
+    fn chain(&self, count: usize) -> [u8; 32] {
+        self.entries[..count].iter().fold(self.base, |head, entry| chain(&head, entry))
+    }
+}
+
+fn sequence(entry: &[u8; ENTRY]) -> u32 {
+    u32::from_be_bytes(entry[..4].try_into().unwrap())
+}
+
+fn chain(head: &[u8; 32], entry: &[u8; ENTRY]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(head);
+    hasher.update(entry);
+    hasher.finalize().into()
+}
+
+/// Appends an entry, dropping the oldest one if the log is full.
+pub(crate) fn append<T: TrussedClient>(trussed: &mut T, event: Event, detail: u32) -> Result<(), ()> {
+    let mut log = Log::load(trussed);
+    let mut entry = [0u8; ENTRY];
+    entry[..4].copy_from_slice(&log.next_sequence().to_be_bytes());
+    entry[4] = event as u8;
+    entry[5..].copy_from_slice(&detail.to_be_bytes());
+
+    if log.len == CAPACITY {
+        log.base = chain(&log.base, &log.entries[0]);
+        log.entries.copy_within(1.., 0);
+        log.len -= 1;
+    }
+    log.entries[log.len] = entry;
+    log.len += 1;
+    log.store(trussed)
+}
+
+/// Records a `Reset` entry if the platform counted more factory resets
+/// than recorded: the wipe removed the log along with the count.
+pub(crate) fn record_resets<T: TrussedClient>(trussed: &mut T, count: u32) -> Result<(), ()> {
+    let recorded = try_syscall!(trussed.read_file(Location::Internal, PathBuf::from(RESETS_PATH)))
+        .ok()
+        .and_then(|reply| reply.data[..].try_into().ok())
+        .map_or(0, u32::from_be_bytes);
+    if count <= recorded {
+        return Ok(());
+    }
+    append(trussed, Event::Reset, count)?;
+    let data = Message::from_slice(&count.to_be_bytes())?;
+    try_syscall!(trussed.write_file(Location::Internal, PathBuf::from(RESETS_PATH), data, None))
+        .map(drop)
+        .map_err(drop)
+}
+
+/// Removes the log, the chain restarts from zero. The count of
+/// recorded resets is kept.
+pub(crate) fn remove<T: TrussedClient>(trussed: &mut T) {
+    try_syscall!(trussed.remove_file(Location::Internal, PathBuf::from(LOG_PATH))).ok();
+}
+
+/// Passes a page of entries to `sink`, starting at sequence number `from`
+/// (u32, big endian), or else at the oldest entry kept: the hash preceding
+/// the first entry passed, followed by up to sixteen entries.
+pub(crate) fn read<T: TrussedClient>(trussed: &mut T, from: &[u8], mut sink: impl FnMut(&[u8])) -> Result<(), ()> {
+    let log = Log::load(trussed);
+    let start = match from.len() {
+        0 => 0,
+        4 => {
+            let from = u32::from_be_bytes(from.try_into().unwrap());
+            log.entries().iter().position(|entry| sequence(entry) >= from).unwrap_or(log.len)
+        }
+        _ => return Err(()),
+    };
+    sink(&log.chain(start));
+    for entry in log.entries()[start..].iter().take(PAGE) {
+        sink(entry);
+    }
+    Ok(())
+}
+
+/// Returns the log head and the sequence number of the next entry (u32, big endian).
+pub(crate) fn head<T: TrussedClient>(trussed: &mut T) -> [u8; 36] {
+    let log = Log::load(trussed);
+    let mut head = [0u8; 36];
+    head[..32].copy_from_slice(&log.chain(log.len));
+    head[32..].copy_from_slice(&log.next_sequence().to_be_bytes());
+    head
+}
@@ -0,0 +1,215 @@
+//! Secure messaging for APDU admin commands.
+//!
+//! After SELECT, the host opens a channel by sending an ephemeral P-256 public
+//! key with the SECURE_CHANNEL instruction (0x69); the device replies with its
+//! own ephemeral public key and, if it has an attestation key, a signature over
+//! the label `admin-app channel` (NUL terminated) and both keys. SELECT and
+//! deselect close the channel. Both sides derive
+//! an encryption key `K_enc = SHA-256(Z)` and a MAC key `K_mac = SHA-256(K_enc)`
+//! from the ECDH shared secret `Z`.
+//!
+//! While the channel is open, command and response data are wrapped as
+//! `C || MAC`, where `C` is the AES-256-CBC encryption (zero IV) of a counter
+//! block followed by the data, padded as per ISO/IEC 9797-1 method 2, and `MAC`
+//! the HMAC-SHA256, truncated to 16 bytes, over INS, P1, P2 and `C` for commands,
+//! and over `C` for responses. The counter block is sixteen bytes: a direction
+//! byte (0x00 for commands, 0x01 for responses), zeros, and the command counter
+//! (u32, big endian) starting at one, which rejects replayed commands.
+use apdu_dispatch::iso7816::Command;
+use trussed::{
+    syscall, try_syscall,
+    Client as TrussedClient,
+    config::MAX_MESSAGE_LENGTH,
+    types::{KeyId, KeySerialization, Location, Mechanism, Message, SignatureSerialization, StorageAttributes},
+};
+use crate::attestation::{Attestation, Context};
+
+const BLOCK: usize = 16;
+const MAC_LENGTH: usize = 16;
+const COMMAND: u8 = 0x00;
+const RESPONSE: u8 = 0x01;
+// Secure messaging indication bits of the interindustry class byte.
+const SECURE_MESSAGING: u8 = 0x0C;
+
+/// Longest data that can be wrapped or unwrapped.
+pub(crate) const MAX_DATA: usize = MAX_MESSAGE_LENGTH - 2 * BLOCK - MAC_LENGTH;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Error {
+    /// The host key or the wrapped data are malformed.
+    Malformed,
+    /// The MAC or the counter does not match, the channel must be reopened.
+    Integrity,
+    /// Trussed failed to perform a cryptographic operation.
+    Trussed,
+}
+
+pub(crate) struct Channel {
+    enc: KeyId,
+    mac: KeyId,
+    counter: u32,
+}
+
+fn volatile() -> StorageAttributes {
+    StorageAttributes::new().set_persistence(Location::Volatile)
+}
+
+impl Channel {
+    /// Opens a channel with the host ephemeral public key `host_public_key` (64 bytes, raw),
+    /// passing the device ephemeral public key (64 bytes, raw), followed by the DER
+    /// signature of the attestation key over both keys if there is one, to `sink`.
+    pub fn open<T: TrussedClient>(
+        trussed: &mut T,
+        host_public_key: &[u8],
+        attestation: Option<&Attestation>,
+        mut sink: impl FnMut(&[u8]),
+    ) -> Result<Self, Error> {
+        if host_public_key.len() != 64 {
+            return Err(Error::Malformed);
+        }
+        let host_key = try_syscall!(trussed.deserialize_key(Mechanism::P256, host_public_key, KeySerialization::Raw, volatile()))
+            .map_err(|_| Error::Malformed)?
+            .key;
+
+        let private_key = syscall!(trussed.generate_key(Mechanism::P256, volatile())).key;
+        let public_key = syscall!(trussed.derive_key(Mechanism::P256, private_key, None, volatile())).key;
+        let device_key = syscall!(trussed.serialize_key(Mechanism::P256, public_key, KeySerialization::Raw)).serialized_key;
+        let shared_secret = try_syscall!(trussed.agree(Mechanism::P256, private_key, host_key, volatile()))
+            .map(|reply| reply.shared_secret);
+        for key in [host_key, private_key, public_key] {
+            syscall!(trussed.delete(key));
+        }
+        let shared_secret = shared_secret.map_err(|_| Error::Trussed)?;
+
+        let enc = syscall!(trussed.derive_key(Mechanism::Sha256, shared_secret, None, volatile())).key;
+        let mac = syscall!(trussed.derive_key(Mechanism::Sha256, enc, None, volatile())).key;
+        syscall!(trussed.delete(shared_secret));
+        let channel = Self { enc, mac, counter: 0 };
+
+        sink(&device_key);
+        if let Some(attestation) = attestation {
+            let mut keys = [0u8; 128];
+            keys[..64].copy_from_slice(host_public_key);
+            keys[64..].copy_from_slice(&device_key);
+            match attestation.sign(trussed, Context::Channel, &keys) {
+                Ok(signature) => sink(&signature),
+                Err(_) => {
+                    channel.close(trussed);
+                    return Err(Error::Trussed);
+                }
+            }
+        }
+        Ok(channel)
+    }
+
+    /// Closes the channel, deleting its keys.
+    pub fn close<T: TrussedClient>(self, trussed: &mut T) {
+        syscall!(trussed.delete(self.enc));
+        syscall!(trussed.delete(self.mac));
+    }
+
+    /// Verifies and decrypts the data of the command with header `INS, P1, P2`.
+    pub fn unwrap<T: TrussedClient>(&mut self, trussed: &mut T, header: [u8; 3], data: &[u8]) -> Result<Message, Error> {
+        if data.len() < 2 * BLOCK + MAC_LENGTH || (data.len() - MAC_LENGTH) % BLOCK != 0 {
+            return Err(Error::Malformed);
+        }
+        let (ciphertext, mac) = data.split_at(data.len() - MAC_LENGTH);
+
+        let mut authenticated = Message::new();
+        authenticated.extend_from_slice(&header).map_err(|_| Error::Malformed)?;
+        authenticated.extend_from_slice(ciphertext).map_err(|_| Error::Malformed)?;
+        if !equal(&self.authenticate(trussed, &authenticated)?[..MAC_LENGTH], mac) {
+            return Err(Error::Integrity);
+        }
+
+        let plaintext = try_syscall!(trussed.decrypt(Mechanism::Aes256Cbc, self.enc, ciphertext, &[], &[], &[]))
+            .map_err(|_| Error::Trussed)?
+            .plaintext
+            .ok_or(Error::Trussed)?;
+        let counter = self.counter.wrapping_add(1);
+        if plaintext[..BLOCK] != counter_block(COMMAND, counter) {
+            return Err(Error::Integrity);
+        }
+        self.counter = counter;
+
+        let padded = &plaintext[BLOCK..];
+        let end = padded.iter().rposition(|&byte| byte != 0).ok_or(Error::Malformed)?;
+        if padded[end] != 0x80 {
+            return Err(Error::Malformed);
+        }
+        Message::from_slice(&padded[..end]).map_err(|_| Error::Malformed)
+    }
+
+    /// Encrypts and authenticates the response data to the last command.
+    pub fn wrap<T: TrussedClient>(&mut self, trussed: &mut T, data: &[u8]) -> Result<Message, Error> {
+        if data.len() > MAX_DATA {
+            return Err(Error::Malformed);
+        }
+        let mut plaintext = Message::new();
+        plaintext.extend_from_slice(&counter_block(RESPONSE, self.counter)).ok();
+        plaintext.extend_from_slice(data).ok();
+        plaintext.extend_from_slice(&[0x80]).ok();
+        while plaintext.len() % BLOCK != 0 {
+            plaintext.extend_from_slice(&[0x00]).ok();
+        }
+
+        let mut wrapped = try_syscall!(trussed.encrypt(Mechanism::Aes256Cbc, self.enc, &plaintext, &[], None))
+            .map_err(|_| Error::Trussed)?
+            .ciphertext;
+        let mac = self.authenticate(trussed, &wrapped)?;
+        wrapped.extend_from_slice(&mac[..MAC_LENGTH]).map_err(|_| Error::Malformed)?;
+        Ok(wrapped)
+    }
+
+    fn authenticate<T: TrussedClient>(&self, trussed: &mut T, data: &[u8]) -> Result<[u8; 32], Error> {
+        let signature = try_syscall!(trussed.sign(Mechanism::HmacSha256, self.mac, data, SignatureSerialization::Raw))
+            .map_err(|_| Error::Trussed)?
+            .signature;
+        let mut mac = [0u8; 32];
+        mac.copy_from_slice(&signature[..32]);
+        Ok(mac)
+    }
+}
+
+/// Builds the plaintext command with class `class` (without secure messaging
+/// indication), header `INS, P1, P2` and `data`, without Le.
+pub(crate) fn command(class: u8, header: [u8; 3], data: &[u8]) -> Result<Command<MAX_MESSAGE_LENGTH>, Error> {
+    let mut raw = [0u8; 7 + MAX_MESSAGE_LENGTH];
+    raw[0] = class & !SECURE_MESSAGING;
+    raw[1..4].copy_from_slice(&header);
+    let mut length = 4;
+    if !data.is_empty() {
+        if data.len() <= 255 {
+            raw[4] = data.len() as u8;
+            length = 5;
+        } else {
+            raw[5..7].copy_from_slice(&(data.len() as u16).to_be_bytes());
+            length = 7;
+        }
+        raw[length..length + data.len()].copy_from_slice(data);
+        length += data.len();
+    }
+    Command::try_from(&raw[..length]).map_err(|_| Error::Malformed)
+}
+
+fn counter_block(direction: u8, counter: u32) -> [u8; BLOCK] {
+    let mut block = [0u8; BLOCK];
+    block[0] = direction;
+    block[BLOCK - 4..].copy_from_slice(&counter.to_be_bytes());
+    block
+}
+
+/// Compares in constant time.
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+impl From<Error> for crate::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Malformed => crate::Error::Malformed,
+            Error::Integrity => crate::Error::Unauthorized,
+            Error::Trussed => crate::Error::Failed,
+        }
+    }
+}
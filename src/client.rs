@@ -0,0 +1,441 @@
+//! Host-side client for the admin app.
+//!
+//! `AdminClient` encodes the vendor commands, and sends them over a
+//! `Link`: either CTAPHID (`Ctaphid`, over raw HID reports), or
+//! APDUs (`Apdu`, over a smart card connection, e.g. PC/SC).
+//!
+//! No HID or smart card library is pulled in; implement `HidDevice`
+//! or `Card` on top of e.g. `hidapi` or `pcsc`.
+use std::{fmt, io, time::SystemTime};
+use ctaphid_dispatch::command::VendorCommand;
+use crate::admin::{MIN_VERSION, REBOOT, RNG, UPDATE, UPDATE_STATUS, UUID, VERSION};
+use crate::update::{BEGIN, BOOTLOADER, BOOTLOADER_DESTRUCTIVE, FINALIZE, WRITE};
+use crate::version::INFO;
+
+// Image bytes per UPDATE WRITE, fits a CTAPHID message.
+const CHUNK: usize = 512;
+// Random bytes per RNG request, fits the reply of either transport.
+const RANDOM_CHUNK: usize = 1024;
+
+/// Admin app AID, selected by `Apdu` before the first command.
+pub const AID: &[u8] = crate::admin::AID;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The transport failed.
+    Io(io::Error),
+    /// The device disconnected after the command was sent, e.g. rebooting.
+    Disconnected,
+    /// The device replied with a CTAPHID error code.
+    Hid(u8),
+    /// The admin command failed, as per the CTAPHID status byte.
+    /// Commands old hosts send report errors as CTAPHID errors instead.
+    Device(crate::Error),
+    /// The device replied with an ISO 7816 status word other than 9000.
+    Status(u16),
+    /// The reply is malformed, or does not belong to the command.
+    Protocol,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "transport error: {}", error),
+            Error::Disconnected => f.write_str("device disconnected"),
+            Error::Hid(code) => write!(f, "CTAPHID error 0x{:02X}", code),
+            Error::Device(error) => write!(f, "admin command failed: {:?}", error),
+            Error::Status(status) => write!(f, "status word {:04X}", status),
+            Error::Protocol => f.write_str("malformed reply"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+/// Carries admin vendor commands to the device.
+pub trait Link {
+    /// Sends the vendor command `command` and returns the response data.
+    ///
+    /// The `parameters`, a sub-command or a length of at most two bytes,
+    /// are sent as leading payload bytes over CTAPHID, and as P1 and P2
+    /// over APDU (zero if none).
+    fn call(&mut self, command: VendorCommand, parameters: &[u8], data: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Raw HID device, exchanging 64 byte reports (without report ID).
+pub trait HidDevice {
+    fn write(&mut self, report: &[u8; 64]) -> io::Result<()>;
+    fn read(&mut self, report: &mut [u8; 64]) -> io::Result<()>;
+}
+
+/// Smart card connection.
+pub trait Card {
+    /// Transmits a command APDU, returning the response data followed by the status word.
+    ///
+    /// Fails with `io::ErrorKind::ConnectionReset` if the card was reset or
+    /// removed after the command was sent, e.g. on `SCARD_W_RESET_CARD` or
+    /// `SCARD_W_REMOVED_CARD` with PC/SC.
+    fn transmit(&mut self, command: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+const BROADCAST: u32 = 0xFFFF_FFFF;
+const CTAPHID_INIT: u8 = 0x06;
+const CTAPHID_KEEPALIVE: u8 = 0x3B;
+const CTAPHID_ERROR: u8 = 0x3F;
+const INIT_DATA: usize = 64 - 7;
+const CONTINUATION_DATA: usize = 64 - 5;
+const MAX_PAYLOAD: usize = INIT_DATA + 128 * CONTINUATION_DATA;
+
+/// CTAPHID link, over a channel allocated on creation.
+pub struct Ctaphid<D: HidDevice> {
+    device: D,
+    channel: u32,
+}
+
+impl<D: HidDevice> Ctaphid<D> {
+    /// Allocates a CTAPHID channel on `device`.
+    pub fn new(device: D) -> Result<Self> {
+        let mut ctaphid = Self { device, channel: BROADCAST };
+        let nonce = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or_default()
+            .to_be_bytes();
+        let response = ctaphid.transact(CTAPHID_INIT, &nonce)?;
+        if response.len() < 12 || response[..8] != nonce {
+            return Err(Error::Protocol);
+        }
+        ctaphid.channel = u32::from_be_bytes([response[8], response[9], response[10], response[11]]);
+        Ok(ctaphid)
+    }
+
+    /// Returns the HID device, e.g. to allocate a new channel after a reboot.
+    pub fn into_device(self) -> D {
+        self.device
+    }
+
+    /// Sends the CTAPHID command `command` and returns the response payload.
+    pub fn transact(&mut self, command: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(Error::Protocol);
+        }
+        self.send(command, payload)?;
+        // Sent, the device going away now is a disconnect
+        self.receive(command).map_err(|error| match error {
+            Error::Io(_) => Error::Disconnected,
+            error => error,
+        })
+    }
+
+    fn send(&mut self, command: u8, payload: &[u8]) -> Result<()> {
+        let mut report = [0u8; 64];
+        report[..4].copy_from_slice(&self.channel.to_be_bytes());
+        report[4] = 0x80 | command;
+        report[5..7].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+        let (first, rest) = payload.split_at(payload.len().min(INIT_DATA));
+        report[7..7 + first.len()].copy_from_slice(first);
+        self.device.write(&report)?;
+
+        for (sequence, chunk) in rest.chunks(CONTINUATION_DATA).enumerate() {
+            let mut report = [0u8; 64];
+            report[..4].copy_from_slice(&self.channel.to_be_bytes());
+            report[4] = sequence as u8;
+            report[5..5 + chunk.len()].copy_from_slice(chunk);
+            self.device.write(&report)?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, command: u8) -> Result<Vec<u8>> {
+        let mut report = [0u8; 64];
+        // Skip keepalives, and reports for other channels
+        let (response_command, length) = loop {
+            self.device.read(&mut report)?;
+            if report[..4] != self.channel.to_be_bytes() || report[4] & 0x80 == 0 {
+                continue;
+            }
+            let response_command = report[4] & 0x7F;
+            if response_command != CTAPHID_KEEPALIVE {
+                break (response_command, usize::from(u16::from_be_bytes([report[5], report[6]])));
+            }
+        };
+        if length > MAX_PAYLOAD {
+            return Err(Error::Protocol);
+        }
+
+        let mut payload = Vec::with_capacity(length);
+        payload.extend_from_slice(&report[7..7 + length.min(INIT_DATA)]);
+        let mut sequence = 0;
+        while payload.len() < length {
+            self.device.read(&mut report)?;
+            if report[..4] != self.channel.to_be_bytes() {
+                continue;
+            }
+            if report[4] != sequence {
+                return Err(Error::Protocol);
+            }
+            let remaining = (length - payload.len()).min(CONTINUATION_DATA);
+            payload.extend_from_slice(&report[5..5 + remaining]);
+            sequence += 1;
+        }
+
+        match response_command {
+            CTAPHID_ERROR => Err(Error::Hid(payload.first().copied().unwrap_or_default())),
+            response_command if response_command == command => Ok(payload),
+            _ => Err(Error::Protocol),
+        }
+    }
+}
+
+impl<D: HidDevice> Link for Ctaphid<D> {
+    fn call(&mut self, command: VendorCommand, parameters: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let mut payload = Vec::with_capacity(parameters.len() + data.len());
+        payload.extend_from_slice(parameters);
+        payload.extend_from_slice(data);
+        let response = self.transact(command.into(), &payload)?;
+        // Commands old hosts send reply without status byte
+        if crate::admin::legacy(command, &payload) {
+            return Ok(response);
+        }
+        crate::Error::check(&response)
+            .map(<[u8]>::to_vec)
+            .map_err(Error::Device)
+    }
+}
+
+const SUCCESS: u16 = 0x9000;
+const GET_RESPONSE: u8 = 0xC0;
+// Class of vendor commands, accepted whether or not the device maps
+// vendor commands to interindustry instructions.
+const PROPRIETARY: u8 = 0x80;
+// Class bit of all but the last command of a chain.
+const CHAINING: u8 = 0x10;
+const SHORT_DATA: usize = 255;
+
+/// APDU link, over a card the admin app was selected on.
+pub struct Apdu<C: Card> {
+    card: C,
+}
+
+impl<C: Card> Apdu<C> {
+    /// Selects the admin app on `card`.
+    pub fn new(card: C) -> Result<Self> {
+        let mut apdu = Self { card };
+        apdu.transmit(0x00, 0xA4, 0x04, 0x00, AID)?;
+        Ok(apdu)
+    }
+
+    /// Returns the card, e.g. to select another application on it.
+    pub fn into_card(self) -> C {
+        self.card
+    }
+
+    /// Sends a command, and returns the response data.
+    ///
+    /// Only short APDUs are used, so any reader will do: data exceeding
+    /// 255 bytes is sent by command chaining, and responses announced with
+    /// status 61xx are collected with GET RESPONSE.
+    pub fn transmit(&mut self, class: u8, instruction: u8, p1: u8, p2: u8, data: &[u8]) -> Result<Vec<u8>> {
+        let mut chunks = data.chunks(SHORT_DATA).peekable();
+        let (mut response, mut status) = loop {
+            let chunk = chunks.next().unwrap_or(&[]);
+            if chunks.peek().is_none() {
+                break self.exchange(&encode(class, instruction, p1, p2, chunk))?;
+            }
+            let (_, status) = self.exchange(&encode(class | CHAINING, instruction, p1, p2, chunk))?;
+            if status != SUCCESS {
+                return Err(Error::Status(status));
+            }
+        };
+        while status >> 8 == 0x61 {
+            let (more, more_status) = self.exchange(&encode(0x00, GET_RESPONSE, 0, 0, &[]))?;
+            response.extend_from_slice(&more);
+            status = more_status;
+        }
+        match status {
+            SUCCESS => Ok(response),
+            status => Err(Error::Status(status)),
+        }
+    }
+
+    fn exchange(&mut self, command: &[u8]) -> Result<(Vec<u8>, u16)> {
+        let mut response = self.card.transmit(command).map_err(|error| match error.kind() {
+            io::ErrorKind::ConnectionReset => Error::Disconnected,
+            _ => Error::Io(error),
+        })?;
+        if response.len() < 2 {
+            return Err(Error::Protocol);
+        }
+        let status = response.split_off(response.len() - 2);
+        Ok((response, u16::from_be_bytes([status[0], status[1]])))
+    }
+}
+
+/// Encodes a short command APDU, `data` may not exceed 255 bytes.
+/// Le is always the maximum.
+fn encode(class: u8, instruction: u8, p1: u8, p2: u8, data: &[u8]) -> Vec<u8> {
+    let mut command = vec![class, instruction, p1, p2];
+    if !data.is_empty() {
+        command.push(data.len() as u8);
+        command.extend_from_slice(data);
+    }
+    command.push(0x00);
+    command
+}
+
+impl<C: Card> Link for Apdu<C> {
+    fn call(&mut self, command: VendorCommand, parameters: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let p1 = parameters.first().copied().unwrap_or(0);
+        let p2 = parameters.get(1).copied().unwrap_or(0);
+        self.transmit(PROPRIETARY, command.into(), p1, p2, data)
+    }
+}
+
+/// Status of the current or last in-band update, see `AdminClient::update_status`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UpdateStatus {
+    /// Idle (0), receiving (1), verifying (2), staged (3) or failed (4).
+    pub state: u8,
+    /// Failure reason, zero unless failed.
+    pub reason: u8,
+    /// Bytes received, an interrupted upload can be resumed here.
+    pub received: u32,
+    /// Announced image length.
+    pub length: u32,
+}
+
+/// Typed admin commands, over any `Link`.
+pub struct AdminClient<L: Link> {
+    link: L,
+}
+
+impl<L: Link> AdminClient<L> {
+    pub fn new(link: L) -> Self {
+        Self { link }
+    }
+
+    pub fn into_inner(self) -> L {
+        self.link
+    }
+
+    /// Returns the firmware version number.
+    pub fn version(&mut self) -> Result<u32> {
+        let response = self.link.call(VERSION, &[], &[])?;
+        be_u32(&response)
+    }
+
+    /// Returns the CBOR encoded version information.
+    pub fn version_info(&mut self) -> Result<Vec<u8>> {
+        self.link.call(VERSION, &[INFO], &[])
+    }
+
+    /// Returns the device UUID.
+    pub fn uuid(&mut self) -> Result<[u8; 16]> {
+        let response = self.link.call(UUID, &[], &[])?;
+        response.try_into().map_err(|_| Error::Protocol)
+    }
+
+    /// Returns `count` random bytes.
+    ///
+    /// Requests up to `RANDOM_CHUNK` bytes at once.
+    pub fn rng(&mut self, count: usize) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(count);
+        while bytes.len() < count {
+            let length = (count - bytes.len()).min(RANDOM_CHUNK) as u16;
+            let response = self.link.call(RNG, &length.to_be_bytes(), &[])?;
+            if response.len() != usize::from(length) {
+                return Err(Error::Protocol);
+            }
+            bytes.extend_from_slice(&response);
+        }
+        Ok(bytes)
+    }
+
+    /// Returns the persisted minimum firmware version.
+    pub fn min_version(&mut self) -> Result<u32> {
+        let response = self.link.call(MIN_VERSION, &[], &[])?;
+        be_u32(&response)
+    }
+
+    /// Raises the persisted minimum firmware version, needs user presence.
+    pub fn raise_min_version(&mut self, version: u32) -> Result<u32> {
+        let response = self.link.call(MIN_VERSION, &[], &version.to_be_bytes())?;
+        be_u32(&response)
+    }
+
+    /// Reboots the device.
+    pub fn reboot(&mut self) -> Result<()> {
+        rebooted(self.link.call(REBOOT, &[], &[]))
+    }
+
+    /// Reboots the device into the bootloader, needs user presence.
+    pub fn update(&mut self) -> Result<()> {
+        rebooted(self.link.call(UPDATE, &[BOOTLOADER], &[]))
+    }
+
+    /// Reboots the device into the bootloader the destructive way, needs user presence.
+    pub fn update_destructive(&mut self) -> Result<()> {
+        rebooted(self.link.call(UPDATE, &[BOOTLOADER_DESTRUCTIVE], &[]))
+    }
+
+    /// Returns the status of the current or last in-band update.
+    pub fn update_status(&mut self) -> Result<UpdateStatus> {
+        let response = self.link.call(UPDATE_STATUS, &[], &[])?;
+        if response.len() != 10 {
+            return Err(Error::Protocol);
+        }
+        Ok(UpdateStatus {
+            state: response[0],
+            reason: response[1],
+            received: be_u32(&response[2..6])?,
+            length: be_u32(&response[6..])?,
+        })
+    }
+
+    /// Uploads a firmware image in-band, the device reboots into it once
+    /// its Ed25519 `signature` verifies. Starting needs user presence.
+    pub fn upload(&mut self, version: u32, image: &[u8], signature: &[u8; 64]) -> Result<()> {
+        let length = u32::try_from(image.len()).map_err(|_| Error::Protocol)?;
+        let mut begin = length.to_be_bytes().to_vec();
+        begin.extend_from_slice(&version.to_be_bytes());
+        self.link.call(UPDATE, &[BEGIN], &begin)?;
+
+        for (index, chunk) in image.chunks(CHUNK).enumerate() {
+            let mut write = ((index * CHUNK) as u32).to_be_bytes().to_vec();
+            write.extend_from_slice(chunk);
+            self.link.call(UPDATE, &[WRITE], &write)?;
+        }
+
+        rebooted(self.link.call(UPDATE, &[FINALIZE], signature))
+    }
+}
+
+/// The device reboots without replying, disconnecting once the command was
+/// sent means success.
+fn rebooted(result: Result<Vec<u8>>) -> Result<()> {
+    match result {
+        Ok(_) | Err(Error::Disconnected) => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+fn be_u32(data: &[u8]) -> Result<u32> {
+    data.try_into()
+        .map(u32::from_be_bytes)
+        .map_err(|_| Error::Protocol)
+}
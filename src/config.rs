@@ -0,0 +1,163 @@
+//! Persistent device configuration.
+//!
+//! Settings are identified by a one byte id, and stored in the Trussed
+//! filesystem as a sequence of (id, length, value) records, the same
+//! encoding CONFIG_LIST replies with.
+use trussed::{
+    try_syscall,
+    Client as TrussedClient,
+    types::{Location, Message, PathBuf},
+};
+
+const CONFIG_PATH: &str = "config";
+
+// Setting ids.
+const USER_PRESENCE_TIMEOUT: u8 = 0x01;
+const WINK_DURATION: u8 = 0x02;
+const NFC_POLICY: u8 = 0x03;
+const LEGACY_INSTRUCTIONS: u8 = 0x04;
+const SETTINGS: [u8; 4] = [USER_PRESENCE_TIMEOUT, WINK_DURATION, NFC_POLICY, LEGACY_INSTRUCTIONS];
+
+/// Which admin commands are available over NFC.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum NfcPolicy {
+    /// All but those changing the firmware or wiping the device.
+    Restricted = 0x00,
+    /// None.
+    Disabled = 0x01,
+    /// Like `Restricted`, but only over an open secure channel.
+    Secure = 0x02,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Error {
+    /// There is no setting with the requested id.
+    Unknown,
+    /// The value is malformed or out of range.
+    Invalid,
+    /// The Trussed filesystem failed.
+    Storage,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Config {
+    /// Timeout of user presence checks in milliseconds (u32).
+    pub user_presence_timeout: u32,
+    /// Wink duration in milliseconds (u32).
+    pub wink_duration: u32,
+    /// NFC policy (u8).
+    pub nfc_policy: NfcPolicy,
+    /// Whether vendor commands are also accepted as instructions of
+    /// the interindustry class, as by legacy hosts (u8, 0 or 1).
+    pub legacy_instructions: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            user_presence_timeout: 15_000,
+            wink_duration: 10_000,
+            nfc_policy: NfcPolicy::Restricted,
+            legacy_instructions: true,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the persisted configuration, falling back to defaults
+    /// for settings that were never set.
+    pub fn load<T: TrussedClient>(trussed: &mut T) -> Self {
+        let mut config = Self::default();
+        if let Ok(reply) = try_syscall!(trussed.read_file(Location::Internal, PathBuf::from(CONFIG_PATH))) {
+            let mut records: &[u8] = &reply.data;
+            while let [id, length, rest @ ..] = records {
+                let length = usize::from(*length).min(rest.len());
+                let (value, rest) = rest.split_at(length);
+                // Skip settings of other firmware versions
+                config.set(*id, value).ok();
+                records = rest;
+            }
+        }
+        config
+    }
+
+    /// Persists the configuration.
+    pub fn store<T: TrussedClient>(&self, trussed: &mut T) -> Result<(), Error> {
+        let mut records = Message::new();
+        self.list(|bytes| { records.extend_from_slice(bytes).ok(); });
+        try_syscall!(trussed.write_file(Location::Internal, PathBuf::from(CONFIG_PATH), records, None))
+            .map_err(|_| Error::Storage)?;
+        Ok(())
+    }
+
+    /// Removes the persisted configuration, restoring the defaults.
+    pub fn remove<T: TrussedClient>(trussed: &mut T) {
+        try_syscall!(trussed.remove_file(Location::Internal, PathBuf::from(CONFIG_PATH))).ok();
+    }
+
+    /// Passes the encoded value of setting `id` to `sink`.
+    pub fn get(&self, id: u8, mut sink: impl FnMut(&[u8])) -> Result<(), Error> {
+        match id {
+            USER_PRESENCE_TIMEOUT => sink(&self.user_presence_timeout.to_be_bytes()),
+            WINK_DURATION => sink(&self.wink_duration.to_be_bytes()),
+            NFC_POLICY => sink(&[self.nfc_policy as u8]),
+            LEGACY_INSTRUCTIONS => sink(&[u8::from(self.legacy_instructions)]),
+            _ => return Err(Error::Unknown),
+        }
+        Ok(())
+    }
+
+    /// Sets setting `id`, payload is its encoded value.
+    pub fn set(&mut self, id: u8, value: &[u8]) -> Result<(), Error> {
+        match id {
+            USER_PRESENCE_TIMEOUT => self.user_presence_timeout = be_u32(value, 1_000..=60_000)?,
+            WINK_DURATION => self.wink_duration = be_u32(value, 1..=60_000)?,
+            NFC_POLICY => self.nfc_policy = match value {
+                [0x00] => NfcPolicy::Restricted,
+                [0x01] => NfcPolicy::Disabled,
+                [0x02] => NfcPolicy::Secure,
+                _ => return Err(Error::Invalid),
+            },
+            LEGACY_INSTRUCTIONS => self.legacy_instructions = match value {
+                [0x00] => false,
+                [0x01] => true,
+                _ => return Err(Error::Invalid),
+            },
+            _ => return Err(Error::Unknown),
+        }
+        Ok(())
+    }
+
+    /// Passes all settings to `sink`, as (id, length, value) records.
+    pub fn list(&self, mut sink: impl FnMut(&[u8])) {
+        for id in SETTINGS {
+            let mut value = [0u8; 4];
+            let mut length = 0;
+            self.get(id, |bytes| {
+                value[..bytes.len()].copy_from_slice(bytes);
+                length = bytes.len();
+            }).ok();
+            sink(&[id, length as u8]);
+            sink(&value[..length]);
+        }
+    }
+}
+
+fn be_u32(value: &[u8], range: core::ops::RangeInclusive<u32>) -> Result<u32, Error> {
+    let value = value.try_into().map(u32::from_be_bytes).map_err(|_| Error::Invalid)?;
+    if range.contains(&value) {
+        Ok(value)
+    } else {
+        Err(Error::Invalid)
+    }
+}
+
+impl From<Error> for crate::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Unknown => crate::Error::Unsupported,
+            Error::Invalid => crate::Error::Invalid,
+            Error::Storage => crate::Error::Storage,
+        }
+    }
+}
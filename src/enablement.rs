@@ -0,0 +1,186 @@
+//! Enabling and disabling applications and transports.
+//!
+//! Choices are persisted in the Trussed filesystem and take effect on the
+//! next boot, when the firmware queries them through `Availability`.
+use trussed::{
+    try_syscall,
+    Client as TrussedClient,
+    types::{Location, Message, PathBuf},
+};
+
+const ENABLEMENT_PATH: &str = "enablement";
+const MAX_AID: usize = 16;
+const MAX_DISABLED: usize = 16;
+
+/// Transport the device can be reached by.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Transport {
+    UsbCcid = 0x01,
+    UsbCtaphid = 0x02,
+    Nfc = 0x04,
+}
+
+const ALL_TRANSPORTS: u8 = Transport::UsbCcid as u8 | Transport::UsbCtaphid as u8 | Transport::Nfc as u8;
+// The admin app must stay reachable over USB.
+const USB_TRANSPORTS: u8 = Transport::UsbCcid as u8 | Transport::UsbCtaphid as u8;
+
+/// Queried by the firmware at boot, to only set up enabled
+/// transports and applications.
+pub trait Availability {
+    fn transport_enabled(&mut self, transport: Transport) -> bool;
+    fn app_enabled(&mut self, aid: &[u8]) -> bool;
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Error {
+    /// The payload is malformed or the AID too long.
+    Malformed,
+    /// The change would lock out the admin app, or too many applications are disabled.
+    Refused,
+    /// The Trussed filesystem failed.
+    Storage,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct Enablement {
+    /// Bitmap of enabled transports.
+    transports: u8,
+    disabled: [[u8; MAX_AID]; MAX_DISABLED],
+    lengths: [u8; MAX_DISABLED],
+    count: usize,
+}
+
+impl Enablement {
+    /// Loads the persisted choices, everything is enabled by default.
+    ///
+    /// Persisted as the transports bitmap, followed by the disabled AIDs,
+    /// each as length (u8) followed by the AID.
+    pub fn load<T: TrussedClient>(trussed: &mut T) -> Self {
+        let mut enablement = Self {
+            transports: ALL_TRANSPORTS,
+            disabled: [[0; MAX_AID]; MAX_DISABLED],
+            lengths: [0; MAX_DISABLED],
+            count: 0,
+        };
+        if let Ok(reply) = try_syscall!(trussed.read_file(Location::Internal, PathBuf::from(ENABLEMENT_PATH))) {
+            if let Some((transports, mut records)) = reply.data.split_first() {
+                if transports & USB_TRANSPORTS != 0 {
+                    enablement.transports = transports & ALL_TRANSPORTS;
+                }
+                while let [length, rest @ ..] = records {
+                    let length = usize::from(*length).min(rest.len());
+                    let (aid, rest) = rest.split_at(length);
+                    enablement.disable(aid).ok();
+                    records = rest;
+                }
+            }
+        }
+        enablement
+    }
+
+    fn store<T: TrussedClient>(&self, trussed: &mut T) -> Result<(), Error> {
+        let mut data = Message::new();
+        data.extend_from_slice(&[self.transports]).ok();
+        self.list(|bytes| { data.extend_from_slice(bytes).ok(); });
+        try_syscall!(trussed.write_file(Location::Internal, PathBuf::from(ENABLEMENT_PATH), data, None))
+            .map_err(|_| Error::Storage)?;
+        Ok(())
+    }
+
+    /// Removes the persisted choices, enabling everything.
+    pub fn remove<T: TrussedClient>(trussed: &mut T) {
+        try_syscall!(trussed.remove_file(Location::Internal, PathBuf::from(ENABLEMENT_PATH))).ok();
+    }
+
+    fn disabled(&self) -> impl Iterator<Item = &[u8]> {
+        self.disabled[..self.count]
+            .iter()
+            .zip(self.lengths.iter())
+            .map(|(aid, &length)| &aid[..usize::from(length)])
+    }
+
+    fn position(&self, aid: &[u8]) -> Option<usize> {
+        self.disabled().position(|disabled| disabled == aid)
+    }
+
+    fn disable(&mut self, aid: &[u8]) -> Result<(), Error> {
+        if aid.is_empty() || aid.len() > MAX_AID {
+            return Err(Error::Malformed);
+        }
+        if self.position(aid).is_some() {
+            return Ok(());
+        }
+        if self.count == MAX_DISABLED {
+            return Err(Error::Refused);
+        }
+        self.disabled[self.count][..aid.len()].copy_from_slice(aid);
+        self.lengths[self.count] = aid.len() as u8;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn enable(&mut self, aid: &[u8]) {
+        if let Some(index) = self.position(aid) {
+            self.disabled.copy_within(index + 1..self.count, index);
+            self.lengths.copy_within(index + 1..self.count, index);
+            self.count -= 1;
+        }
+    }
+
+    pub fn transports(&self) -> u8 {
+        self.transports
+    }
+
+    pub fn transport_enabled(&self, transport: Transport) -> bool {
+        self.transports & transport as u8 != 0
+    }
+
+    pub fn app_enabled(&self, aid: &[u8]) -> bool {
+        self.position(aid).is_none()
+    }
+
+    /// Sets and persists the bitmap of enabled transports; at least one
+    /// USB transport must remain enabled.
+    pub fn set_transports<T: TrussedClient>(&mut self, trussed: &mut T, transports: u8) -> Result<(), Error> {
+        if transports & !ALL_TRANSPORTS != 0 {
+            return Err(Error::Malformed);
+        }
+        if transports & USB_TRANSPORTS == 0 {
+            return Err(Error::Refused);
+        }
+        self.transports = transports;
+        self.store(trussed)
+    }
+
+    /// Enables or disables and persists the application with AID `aid`.
+    /// The admin app itself (`own_aid`) cannot be disabled.
+    pub fn set_app<T: TrussedClient>(&mut self, trussed: &mut T, aid: &[u8], enabled: bool, own_aid: &[u8]) -> Result<(), Error> {
+        if enabled {
+            self.enable(aid);
+        } else if aid == own_aid {
+            return Err(Error::Refused);
+        } else {
+            self.disable(aid)?;
+        }
+        self.store(trussed)
+    }
+
+    /// Passes the disabled AIDs to `sink`, each as length (u8) followed by the AID.
+    pub fn list(&self, mut sink: impl FnMut(&[u8])) {
+        for aid in self.disabled() {
+            sink(&[aid.len() as u8]);
+            sink(aid);
+        }
+    }
+}
+
+impl From<Error> for crate::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Malformed => crate::Error::Malformed,
+            Error::Refused => crate::Error::Invalid,
+            Error::Storage => crate::Error::Storage,
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Admin error model, shared by all vendor commands.
+//!
+//! Over CTAPHID, vendor command responses start with a status byte: zero on
+//! success, followed by the response data, or else the error code, followed
+//! by its detail if any. Over APDU, errors map to ISO 7816 status words;
+//! as there are fewer of those, some errors share a status word.
+//!
+//! The commands old hosts send over CTAPHID reply without status byte, as
+//! the original firmware did; their errors map to CTAPHID errors.
+use apdu_dispatch::iso7816::Status;
+use ctaphid_dispatch::app as hid;
+
+const WRONG_PIN: u8 = 0x09;
+
+/// Reason an admin command failed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The user denied presence, or the interface may not perform the operation.
+    Denied,
+    /// User presence was not confirmed in time.
+    Timeout,
+    /// The command, sub-command or feature is not supported.
+    Unsupported,
+    /// Another operation holds the user interface.
+    Busy,
+    /// The Trussed filesystem, or the platform storage, failed.
+    Storage,
+    /// The payload is malformed.
+    Malformed,
+    /// The payload is well-formed but rejected, e.g. out of range.
+    Invalid,
+    /// The admin PIN must be verified first, or the secure channel
+    /// must be reopened.
+    Unauthorized,
+    /// The admin PIN is wrong, with the number of retries left.
+    WrongPin(u8),
+    /// The admin PIN is blocked.
+    Blocked,
+    /// Trussed failed to perform an operation.
+    Failed,
+    /// The host cancelled the command while waiting for user presence.
+    Cancelled,
+    /// A signature does not verify, e.g. over a firmware image.
+    Signature,
+    /// The command is out of sequence, e.g. an image chunk before the
+    /// upload was begun, or not continuing the chunks received.
+    Sequence,
+}
+
+impl Error {
+    /// CTAPHID status code.
+    pub fn code(&self) -> u8 {
+        match self {
+            Error::Denied => 0x01,
+            Error::Timeout => 0x02,
+            Error::Unsupported => 0x03,
+            Error::Busy => 0x04,
+            Error::Storage => 0x05,
+            Error::Malformed => 0x06,
+            Error::Invalid => 0x07,
+            Error::Unauthorized => 0x08,
+            Error::WrongPin(_) => WRONG_PIN,
+            Error::Blocked => 0x0A,
+            Error::Failed => 0x0B,
+            Error::Cancelled => 0x0C,
+            Error::Signature => 0x0D,
+            Error::Sequence => 0x0E,
+        }
+    }
+
+    /// Passes the CTAPHID status, code followed by detail if any, to `sink`.
+    pub(crate) fn encode(&self, mut sink: impl FnMut(&[u8])) {
+        sink(&[self.code()]);
+        if let Error::WrongPin(retries) = self {
+            sink(&[*retries]);
+        }
+    }
+
+    /// Splits a CTAPHID vendor command response into the response data
+    /// on success, or the error. Unknown codes are reported as `Failed`.
+    pub fn check(response: &[u8]) -> Result<&[u8], Error> {
+        let error = match response {
+            [0x00, data @ ..] => return Ok(data),
+            [WRONG_PIN, retries, ..] => Error::WrongPin(*retries),
+            [0x01, ..] => Error::Denied,
+            [0x02, ..] => Error::Timeout,
+            [0x03, ..] => Error::Unsupported,
+            [0x04, ..] => Error::Busy,
+            [0x05, ..] => Error::Storage,
+            [0x06, ..] => Error::Malformed,
+            [0x07, ..] => Error::Invalid,
+            [0x08, ..] => Error::Unauthorized,
+            [0x0A, ..] => Error::Blocked,
+            [0x0C, ..] => Error::Cancelled,
+            [0x0D, ..] => Error::Signature,
+            [0x0E, ..] => Error::Sequence,
+            _ => Error::Failed,
+        };
+        Err(error)
+    }
+}
+
+impl From<Error> for Status {
+    fn from(error: Error) -> Self {
+        match error {
+            // As with U2F, missing user presence is a condition of use
+            Error::Denied | Error::Timeout | Error::Cancelled => Status::ConditionsOfUseNotSatisfied,
+            Error::Sequence => Status::ConditionsOfUseNotSatisfied,
+            Error::Signature => Status::VerificationFailed,
+            Error::Unsupported => Status::FunctionNotSupported,
+            Error::Busy | Error::Failed => Status::UnspecifiedNonpersistentExecutionError,
+            Error::Storage => Status::UnspecifiedPersistentExecutionError,
+            Error::Malformed => Status::WrongLength,
+            Error::Invalid => Status::IncorrectDataParameter,
+            Error::Unauthorized => Status::SecurityStatusNotSatisfied,
+            Error::WrongPin(retries) => Status::RemainingRetries(retries),
+            Error::Blocked => Status::OperationBlocked,
+        }
+    }
+}
+
+impl From<Error> for hid::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Unsupported => hid::Error::InvalidCommand,
+            // As the original firmware reported denied user presence
+            _ => hid::Error::InvalidLength,
+        }
+    }
+}
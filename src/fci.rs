@@ -0,0 +1,59 @@
+//! File control information, returned by SELECT.
+//!
+//! FCI template (tag 6F) with the DF name (84, the AID) and proprietary
+//! data (A5): protocol version (80, u8), firmware version (81, u32 big
+//! endian), UUID (82, omitted if not readable in the session) and the
+//! supported instructions (83).
+
+const FCI_TEMPLATE: u8 = 0x6F;
+const DF_NAME: u8 = 0x84;
+const PROPRIETARY: u8 = 0xA5;
+const PROTOCOL_VERSION: u8 = 0x80;
+const VERSION: u8 = 0x81;
+const UUID: u8 = 0x82;
+const INSTRUCTIONS: u8 = 0x83;
+
+/// Passes the FCI template to `sink`.
+pub(crate) fn encode(
+    aid: &[u8],
+    protocol_version: u8,
+    version: u32,
+    uuid: Option<&[u8; 16]>,
+    instructions: &[u8],
+    mut sink: impl FnMut(&[u8]),
+) {
+    let proprietary = tlv_length(1) + tlv_length(4)
+        + uuid.map_or(0, |uuid| tlv_length(uuid.len()))
+        + tlv_length(instructions.len());
+    header(FCI_TEMPLATE, tlv_length(aid.len()) + tlv_length(proprietary), &mut sink);
+
+    header(DF_NAME, aid.len(), &mut sink);
+    sink(aid);
+
+    header(PROPRIETARY, proprietary, &mut sink);
+    header(PROTOCOL_VERSION, 1, &mut sink);
+    sink(&[protocol_version]);
+    header(VERSION, 4, &mut sink);
+    sink(&version.to_be_bytes());
+    if let Some(uuid) = uuid {
+        header(UUID, uuid.len(), &mut sink);
+        sink(uuid);
+    }
+    header(INSTRUCTIONS, instructions.len(), &mut sink);
+    sink(instructions);
+}
+
+/// Length of a data object with `length` bytes of value.
+fn tlv_length(length: usize) -> usize {
+    let header = if length < 0x80 { 2 } else { 3 };
+    header + length
+}
+
+/// Passes tag and BER-TLV length (at most 255) to `sink`.
+fn header(tag: u8, length: usize, sink: &mut impl FnMut(&[u8])) {
+    if length < 0x80 {
+        sink(&[tag, length as u8]);
+    } else {
+        sink(&[tag, 0x81, length as u8]);
+    }
+}
@@ -0,0 +1,87 @@
+//! RNG health tests.
+//!
+//! Implements the repetition count and adaptive proportion tests of
+//! NIST SP 800-90B (section 4.4) over byte samples, assuming full entropy
+//! (H = 8 bits per sample) and a false positive rate of α = 2^-20.
+
+/// Repetition count test cutoff, 1 + ⌈20 / H⌉.
+const RCT_CUTOFF: u32 = 4;
+/// Adaptive proportion test window size for non-binary samples.
+const APT_WINDOW: u32 = 512;
+/// Adaptive proportion test cutoff for H = 8 and W = 512.
+const APT_CUTOFF: u32 = 13;
+
+#[derive(Default)]
+pub(crate) struct HealthTests {
+    samples: u32,
+    rct_sample: u8,
+    rct_run: u32,
+    rct_max_run: u32,
+    rct_failures: u32,
+    apt_sample: u8,
+    apt_position: u32,
+    apt_count: u32,
+    apt_max_count: u32,
+    apt_windows: u32,
+    apt_failures: u32,
+}
+
+impl HealthTests {
+    pub fn feed(&mut self, samples: &[u8]) {
+        for &sample in samples {
+            if self.samples > 0 && sample == self.rct_sample {
+                self.rct_run += 1;
+            } else {
+                self.rct_sample = sample;
+                self.rct_run = 1;
+            }
+            self.rct_max_run = self.rct_max_run.max(self.rct_run);
+            if self.rct_run == RCT_CUTOFF {
+                self.rct_failures += 1;
+            }
+
+            if self.apt_position == 0 {
+                self.apt_sample = sample;
+                self.apt_count = 1;
+            } else if sample == self.apt_sample {
+                self.apt_count += 1;
+                if self.apt_count == APT_CUTOFF {
+                    self.apt_failures += 1;
+                }
+            }
+            self.apt_max_count = self.apt_max_count.max(self.apt_count);
+            self.apt_position += 1;
+            if self.apt_position == APT_WINDOW {
+                self.apt_position = 0;
+                self.apt_windows += 1;
+            }
+
+            self.samples += 1;
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.rct_failures == 0 && self.apt_failures == 0
+    }
+
+    /// Encodes the verdict (0 passed, 1 failed) followed by the counters
+    /// (u32, big endian each): samples, longest repetition, repetition count
+    /// test failures, complete windows, highest count within a window and
+    /// adaptive proportion test failures.
+    pub fn report(&self) -> [u8; 25] {
+        let mut report = [0u8; 25];
+        report[0] = u8::from(!self.passed());
+        let counters = [
+            self.samples,
+            self.rct_max_run,
+            self.rct_failures,
+            self.apt_windows,
+            self.apt_max_count,
+            self.apt_failures,
+        ];
+        for (field, counter) in report[1..].chunks_exact_mut(4).zip(counters.iter()) {
+            field.copy_from_slice(&counter.to_be_bytes());
+        }
+        report
+    }
+}
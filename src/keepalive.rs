@@ -0,0 +1,28 @@
+//! Progress reporting and cancellation while waiting for user presence.
+
+/// Progress of the current command, as per the CTAPHID_KEEPALIVE status byte.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum KeepaliveStatus {
+    Processing = 0x01,
+    UserPresenceNeeded = 0x02,
+}
+
+/// Link to the transport of the current command, provided by the platform.
+///
+/// The app reports `UserPresenceNeeded` before waiting for user presence,
+/// and `Processing` once confirmed. The wait itself is a single Trussed
+/// request: while it is pending, the platform repeats the last status to
+/// the host, over CTAPHID as CTAPHID_KEEPALIVE, and on CTAPHID_CANCEL
+/// interrupts the request, which fails the command as cancelled.
+/// `cancelled` covers a cancel received before the wait starts.
+/// All methods default to doing nothing.
+pub trait Keepalive {
+    /// Reports the progress of the current command to the host.
+    fn keepalive(_status: KeepaliveStatus) {}
+
+    /// Returns whether the host cancelled the current command.
+    fn cancelled() -> bool {
+        false
+    }
+}
@@ -4,7 +4,36 @@
 //! such as firmware upgrade.
 //!
 //! It directly implements the APDU and CTAPHID dispatch App interfaces.
-#![no_std]
+//!
+//! With the `client` feature, the `client` module provides a host-side
+//! client for the admin commands.
+#![cfg_attr(not(feature = "std"), no_std)]
 
 mod admin;
-pub use admin::{App, Reboot};
+mod attestation;
+mod audit;
+mod channel;
+#[cfg(feature = "client")]
+pub mod client;
+mod config;
+mod enablement;
+mod error;
+mod fci;
+mod health;
+mod keepalive;
+mod objects;
+mod pin;
+mod registry;
+mod selftest;
+mod storage;
+mod update;
+mod version;
+pub use admin::{App, Reboot, Slot, SlotError};
+pub use attestation::Attestation;
+pub use enablement::{Availability, Transport};
+pub use error::Error;
+pub use keepalive::{Keepalive, KeepaliveStatus};
+pub use registry::AppInfo;
+pub use storage::{StorageInfo, Usage};
+pub use update::{Stage, StageError};
+pub use version::VersionInfo;
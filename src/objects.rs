@@ -0,0 +1,83 @@
+//! ISO 7816 data objects for the admin functions.
+//!
+//! GET DATA and PUT DATA address data objects by tag in P1-P2, and VERIFY
+//! verifies the admin PIN (reference `80`). Such commands execute as the
+//! equivalent vendor command, under the same policies, so standard middleware
+//! can talk to the admin app. VERIFY without data reports the verification
+//! status (9000, or 63Cx with the retries left), and with P1 = FF ends the
+//! APDU session. Vendor commands themselves are instructions of the proprietary
+//! class (CLA 0x80), or with the legacy mapping, of any class.
+//!
+//! Data objects, with the vendor command equivalent to GET DATA (and PUT DATA):
+//!
+//! | Tag      | Object                          | Equivalent               |
+//! |----------|---------------------------------|--------------------------|
+//! | `0100`   | UUID                            | UUID                     |
+//! | `0101`   | Firmware version number         | VERSION                  |
+//! | `0102`   | Version information (CBOR)      | VERSION, P1 = 1          |
+//! | `0103`   | Minimum firmware version        | MIN_VERSION (raise)      |
+//! | `0104`   | Update status                   | UPDATE_STATUS            |
+//! | `0105`   | Boot slots                      | BOOT_SLOTS               |
+//! | `0106`   | Storage information             | STORAGE_INFO             |
+//! | `0107`   | Installed applications          | LIST_APPS                |
+//! | `0108`   | Enabled transports              | TRANSPORTS (set)         |
+//! | `0109`   | Disabled applications           | APP_ENABLEMENT           |
+//! | `010A`   | PIN status                      | PIN, P1 = 4              |
+//! | `010B`   | Audit log head                  | LOG_HEAD                 |
+//! | `02xx`   | Setting with id `xx`            | CONFIG_GET (CONFIG_SET)  |
+
+pub(crate) const VERIFY: u8 = 0x20;
+pub(crate) const GET_DATA: u8 = 0xCA;
+pub(crate) const PUT_DATA: u8 = 0xDA;
+pub(crate) const INSTRUCTIONS: [u8; 3] = [VERIFY, GET_DATA, PUT_DATA];
+
+/// VERIFY reference (P2) of the admin PIN.
+pub(crate) const PIN_REFERENCE: u8 = 0x80;
+/// VERIFY P1 resetting the verification status.
+pub(crate) const RESET_VERIFICATION: u8 = 0xFF;
+
+/// Class bit of proprietary instructions.
+pub(crate) const PROPRIETARY_CLASS: u8 = 0x80;
+
+// Tags of the settings, the low byte is the setting id.
+const CONFIG: u8 = 0x02;
+
+/// Data object accessed by GET DATA or PUT DATA.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Object {
+    Uuid,
+    Version,
+    VersionInfo,
+    MinVersion,
+    UpdateStatus,
+    BootSlots,
+    StorageInfo,
+    Apps,
+    Transports,
+    DisabledApps,
+    PinStatus,
+    LogHead,
+    Config(u8),
+}
+
+impl Object {
+    /// Returns the object with tag `[p1, p2]`.
+    pub fn from_tag(p1: u8, p2: u8) -> Option<Self> {
+        Some(match [p1, p2] {
+            [0x01, 0x00] => Object::Uuid,
+            [0x01, 0x01] => Object::Version,
+            [0x01, 0x02] => Object::VersionInfo,
+            [0x01, 0x03] => Object::MinVersion,
+            [0x01, 0x04] => Object::UpdateStatus,
+            [0x01, 0x05] => Object::BootSlots,
+            [0x01, 0x06] => Object::StorageInfo,
+            [0x01, 0x07] => Object::Apps,
+            [0x01, 0x08] => Object::Transports,
+            [0x01, 0x09] => Object::DisabledApps,
+            [0x01, 0x0A] => Object::PinStatus,
+            [0x01, 0x0B] => Object::LogHead,
+            [CONFIG, id] => Object::Config(id),
+            _ => return None,
+        })
+    }
+}
@@ -0,0 +1,238 @@
+//! Optional admin PIN.
+//!
+//! Once a PIN is set, privileged commands require a session in which
+//! the PIN has been verified. Wrong PINs count down a retry counter;
+//! once it is exhausted, the PIN is blocked until a factory reset.
+//!
+//! CTAPHID and APDU have a session each. CTAPHID sessions end on LOGOUT,
+//! or after `IDLE_TIMEOUT` without privileged command; as ctaphid-dispatch
+//! does not pass the channel to apps, all CTAPHID channels share one.
+//! APDU sessions end on LOGOUT, SELECT or deselect.
+use core::time::Duration;
+use sha2::{Digest, Sha256};
+use trussed::{
+    syscall, try_syscall,
+    Client as TrussedClient,
+    types::{Location, Message, PathBuf},
+};
+
+const PIN_PATH: &str = "pin";
+const MAX_RETRIES: u8 = 8;
+const MIN_LENGTH: usize = 4;
+const MAX_LENGTH: usize = 64;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// PIN sub-commands (first payload byte over CTAPHID, P1 over APDU).
+pub(crate) const SET: u8 = 0x01;
+pub(crate) const CHANGE: u8 = 0x02;
+pub(crate) const VERIFY: u8 = 0x03;
+pub(crate) const STATUS: u8 = 0x04;
+pub(crate) const LOGOUT: u8 = 0x05;
+
+/// Transport a session is bound to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Session {
+    Ctaphid,
+    Apdu,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Error {
+    /// The payload is malformed, or the PIN too short or too long.
+    Malformed,
+    /// No PIN is set.
+    NotSet,
+    /// A PIN is already set, it can only be changed.
+    AlreadySet,
+    /// The PIN is wrong, with the number of retries left.
+    Wrong(u8),
+    /// No retries are left.
+    Blocked,
+    /// The Trussed filesystem failed.
+    Storage,
+}
+
+/// PIN as persisted: retries left, salt and salted SHA-256 hash.
+struct Stored {
+    retries: u8,
+    salt: [u8; 16],
+    hash: [u8; 32],
+}
+
+impl Stored {
+    fn load<T: TrussedClient>(trussed: &mut T) -> Option<Self> {
+        let data = try_syscall!(trussed.read_file(Location::Internal, PathBuf::from(PIN_PATH))).ok()?.data;
+        if data.len() != 49 {
+            return None;
+        }
+        let mut stored = Self { retries: data[0], salt: [0; 16], hash: [0; 32] };
+        stored.salt.copy_from_slice(&data[1..17]);
+        stored.hash.copy_from_slice(&data[17..]);
+        Some(stored)
+    }
+
+    fn new<T: TrussedClient>(trussed: &mut T, pin: &[u8]) -> Self {
+        let mut salt = [0u8; 16];
+        salt.copy_from_slice(&syscall!(trussed.random_bytes(16)).bytes);
+        Self { retries: MAX_RETRIES, salt, hash: hash(&salt, pin) }
+    }
+
+    fn store<T: TrussedClient>(&self, trussed: &mut T) -> Result<(), Error> {
+        let mut data = Message::new();
+        data.extend_from_slice(&[self.retries]).ok();
+        data.extend_from_slice(&self.salt).ok();
+        data.extend_from_slice(&self.hash).ok();
+        try_syscall!(trussed.write_file(Location::Internal, PathBuf::from(PIN_PATH), data, None))
+            .map_err(|_| Error::Storage)?;
+        Ok(())
+    }
+
+    /// Checks `pin`, counting down the retries before comparing.
+    fn check<T: TrussedClient>(&mut self, trussed: &mut T, pin: &[u8]) -> Result<(), Error> {
+        if self.retries == 0 {
+            return Err(Error::Blocked);
+        }
+        self.retries -= 1;
+        self.store(trussed)?;
+
+        let candidate = hash(&self.salt, pin);
+        let difference = candidate.iter().zip(self.hash.iter()).fold(0, |acc, (a, b)| acc | (a ^ b));
+        if difference != 0 {
+            return Err(Error::Wrong(self.retries));
+        }
+        self.retries = MAX_RETRIES;
+        self.store(trussed)
+    }
+}
+
+fn hash(salt: &[u8; 16], pin: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(pin);
+    hasher.finalize().into()
+}
+
+fn check_length(pin: &[u8]) -> Result<(), Error> {
+    if (MIN_LENGTH..=MAX_LENGTH).contains(&pin.len()) {
+        Ok(())
+    } else {
+        Err(Error::Malformed)
+    }
+}
+
+fn uptime<T: TrussedClient>(trussed: &mut T) -> Duration {
+    syscall!(trussed.uptime()).uptime
+}
+
+#[derive(Default)]
+pub(crate) struct Pin {
+    /// Uptime of the last privileged command, if verified over CTAPHID.
+    ctaphid: Option<Duration>,
+    /// Whether verified over APDU.
+    apdu: bool,
+}
+
+impl Pin {
+    /// Whether privileged commands may be executed in `session`: no PIN is
+    /// set, or it was verified in the session. Restarts the idle timeout.
+    pub fn authorized<T: TrussedClient>(&mut self, trussed: &mut T, session: Session) -> bool {
+        if Stored::load(trussed).is_none() {
+            return true;
+        }
+        if !self.verified(trussed, session) {
+            self.logout(session);
+            return false;
+        }
+        self.start(trussed, session);
+        true
+    }
+
+    /// Whether the PIN was verified in `session`, and the session has not timed out.
+    fn verified<T: TrussedClient>(&self, trussed: &mut T, session: Session) -> bool {
+        match session {
+            Session::Ctaphid => self.ctaphid.is_some_and(|last| uptime(trussed).saturating_sub(last) < IDLE_TIMEOUT),
+            Session::Apdu => self.apdu,
+        }
+    }
+
+    /// Marks the PIN as verified in `session`.
+    fn start<T: TrussedClient>(&mut self, trussed: &mut T, session: Session) {
+        match session {
+            Session::Ctaphid => self.ctaphid = Some(uptime(trussed)),
+            Session::Apdu => self.apdu = true,
+        }
+    }
+
+    /// Ends `session`.
+    pub fn logout(&mut self, session: Session) {
+        match session {
+            Session::Ctaphid => self.ctaphid = None,
+            Session::Apdu => self.apdu = false,
+        }
+    }
+
+    /// Removes the PIN, ending all sessions.
+    pub fn remove<T: TrussedClient>(&mut self, trussed: &mut T) {
+        *self = Self::default();
+        try_syscall!(trussed.remove_file(Location::Internal, PathBuf::from(PIN_PATH))).ok();
+    }
+
+    /// Sets the PIN, payload is the PIN, verifying it in `session`.
+    pub fn set<T: TrussedClient>(&mut self, trussed: &mut T, session: Session, pin: &[u8]) -> Result<(), Error> {
+        check_length(pin)?;
+        if Stored::load(trussed).is_some() {
+            return Err(Error::AlreadySet);
+        }
+        Stored::new(trussed, pin).store(trussed)?;
+        self.start(trussed, session);
+        Ok(())
+    }
+
+    /// Changes the PIN, payload is the length of the current PIN (u8),
+    /// the current PIN and the new PIN.
+    pub fn change<T: TrussedClient>(&mut self, trussed: &mut T, session: Session, data: &[u8]) -> Result<(), Error> {
+        let (length, rest) = data.split_first().ok_or(Error::Malformed)?;
+        let length = usize::from(*length);
+        if rest.len() < length {
+            return Err(Error::Malformed);
+        }
+        let (current, new) = rest.split_at(length);
+        check_length(new)?;
+
+        self.verify(trussed, session, current)?;
+        Stored::new(trussed, new).store(trussed)
+    }
+
+    /// Verifies the PIN for `session`, payload is the PIN.
+    ///
+    /// PINs of invalid length are refused without counting down the retries.
+    pub fn verify<T: TrussedClient>(&mut self, trussed: &mut T, session: Session, pin: &[u8]) -> Result<(), Error> {
+        check_length(pin)?;
+        self.logout(session);
+        let mut stored = Stored::load(trussed).ok_or(Error::NotSet)?;
+        stored.check(trussed, pin)?;
+        self.start(trussed, session);
+        Ok(())
+    }
+
+    /// Encodes whether a PIN is set, the retries left and whether
+    /// the PIN was verified in `session`.
+    pub fn status<T: TrussedClient>(&self, trussed: &mut T, session: Session) -> [u8; 3] {
+        match Stored::load(trussed) {
+            Some(stored) => [1, stored.retries, u8::from(self.verified(trussed, session))],
+            None => [0, MAX_RETRIES, 0],
+        }
+    }
+}
+
+impl From<Error> for crate::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Malformed => crate::Error::Malformed,
+            Error::NotSet | Error::AlreadySet => crate::Error::Invalid,
+            Error::Wrong(retries) => crate::Error::WrongPin(retries),
+            Error::Blocked => crate::Error::Blocked,
+            Error::Storage => crate::Error::Storage,
+        }
+    }
+}
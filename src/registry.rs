@@ -0,0 +1,38 @@
+//! Registry of the applications installed alongside the admin app.
+use ctaphid_dispatch::app::Command as HidCommand;
+
+/// Application installed on the device.
+#[derive(Clone, Copy, Debug)]
+pub struct AppInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    /// AID the application is selected by over APDU, if any.
+    pub aid: Option<&'static [u8]>,
+    /// CTAPHID commands the application handles.
+    pub commands: &'static [HidCommand],
+}
+
+/// Passes the registry to `sink`: the number of applications (u8), and
+/// for each, name, version, AID (empty if none) and CTAPHID command bytes,
+/// each as length (u8) followed by the data.
+pub(crate) fn list(apps: &[AppInfo], mut sink: impl FnMut(&[u8])) {
+    let apps = &apps[..apps.len().min(255)];
+    sink(&[apps.len() as u8]);
+    for app in apps {
+        let mut field = |data: &[u8]| {
+            let data = &data[..data.len().min(255)];
+            sink(&[data.len() as u8]);
+            sink(data);
+        };
+        field(app.name.as_bytes());
+        field(app.version.as_bytes());
+        field(app.aid.unwrap_or(&[]));
+
+        let mut commands = [0u8; 255];
+        let count = app.commands.len().min(255);
+        for (byte, command) in commands.iter_mut().zip(app.commands.iter()) {
+            *byte = (*command).into();
+        }
+        field(&commands[..count]);
+    }
+}
@@ -0,0 +1,99 @@
+//! Device self-test, exercising the Trussed backend end-to-end.
+use trussed::{
+    syscall, try_syscall,
+    Client as TrussedClient,
+    types::{consent, Location, Mechanism, Message, PathBuf, SignatureSerialization, StorageAttributes},
+};
+use crate::health::HealthTests;
+
+const SELF_TEST_PATH: &str = "self-test";
+
+// Checks, as bits of the result bitmap.
+const RNG: u8 = 0x01;
+const KEYS: u8 = 0x02;
+const FILESYSTEM: u8 = 0x04;
+const UI: u8 = 0x08;
+const CHECKS: u8 = RNG | KEYS | FILESYSTEM | UI;
+
+/// Runs all checks, returning the bitmap of checks run and of checks passed.
+pub(crate) fn run<T: TrussedClient>(trussed: &mut T) -> [u8; 2] {
+    let mut passed = 0;
+    if rng(trussed) {
+        passed |= RNG;
+    }
+    if keys(trussed) {
+        passed |= KEYS;
+    }
+    if filesystem(trussed) {
+        passed |= FILESYSTEM;
+    }
+    if ui(trussed) {
+        passed |= UI;
+    }
+    [CHECKS, passed]
+}
+
+/// Random bytes differ between calls and pass the health tests.
+fn rng<T: TrussedClient>(trussed: &mut T) -> bool {
+    let first = syscall!(trussed.random_bytes(512)).bytes;
+    let second = syscall!(trussed.random_bytes(512)).bytes;
+    let mut tests = HealthTests::default();
+    tests.feed(&first);
+    tests.feed(&second);
+    first != second && tests.passed()
+}
+
+/// A P-256 signature made with a fresh key verifies.
+fn keys<T: TrussedClient>(trussed: &mut T) -> bool {
+    let private_key = match try_syscall!(trussed.generate_key(Mechanism::P256, volatile())) {
+        Ok(reply) => reply.key,
+        Err(_) => return false,
+    };
+    let public_key = match try_syscall!(trussed.derive_key(Mechanism::P256, private_key, None, volatile())) {
+        Ok(reply) => reply.key,
+        Err(_) => {
+            try_syscall!(trussed.delete(private_key)).ok();
+            return false;
+        }
+    };
+
+    let message = b"admin-app self-test";
+    let valid = try_syscall!(trussed.sign(Mechanism::P256, private_key, message, SignatureSerialization::Raw))
+        .ok()
+        .and_then(|reply| {
+            try_syscall!(trussed.verify(Mechanism::P256, public_key, message, &reply.signature, SignatureSerialization::Raw)).ok()
+        })
+        .is_some_and(|reply| reply.valid);
+
+    try_syscall!(trussed.delete(public_key)).ok();
+    try_syscall!(trussed.delete(private_key)).ok();
+    valid
+}
+
+fn volatile() -> StorageAttributes {
+    StorageAttributes::new().set_persistence(Location::Volatile)
+}
+
+/// A file written can be read back, and is gone once removed.
+fn filesystem<T: TrussedClient>(trussed: &mut T) -> bool {
+    let path = PathBuf::from(SELF_TEST_PATH);
+    let data = match Message::from_slice(&syscall!(trussed.random_bytes(32)).bytes) {
+        Ok(data) => data,
+        Err(_) => return false,
+    };
+    if try_syscall!(trussed.write_file(Location::Internal, path.clone(), data.clone(), None)).is_err() {
+        return false;
+    }
+    let read = try_syscall!(trussed.read_file(Location::Internal, path.clone())).map(|reply| reply.data);
+    let removed = try_syscall!(trussed.remove_file(Location::Internal, path.clone())).is_ok();
+    let gone = try_syscall!(trussed.read_file(Location::Internal, path)).is_err();
+    read.ok() == Some(data) && removed && gone
+}
+
+/// Requesting user presence returns, timing out immediately when nobody confirms.
+fn ui<T: TrussedClient>(trussed: &mut T) -> bool {
+    match try_syscall!(trussed.confirm_user_present(1)) {
+        Ok(reply) => matches!(reply.result, Ok(()) | Err(consent::Error::TimedOut)),
+        Err(_) => false,
+    }
+}
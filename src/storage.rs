@@ -0,0 +1,49 @@
+//! Storage usage and health reporting.
+use trussed::types::Location;
+
+/// Filesystem statistics, provided by the platform.
+///
+/// Trussed clients only see their own files, so the platform reports
+/// usage on behalf of all of them. All methods default to "unknown".
+pub trait StorageInfo {
+    /// Returns the usage of the filesystem at `location`.
+    fn usage(_location: Location) -> Option<Usage> {
+        None
+    }
+
+    /// Returns the wear of the filesystem at `location`,
+    /// in percent of the rated erase cycles.
+    fn wear(_location: Location) -> Option<u8> {
+        None
+    }
+
+    /// Passes the name and number of files of each Trussed client to `client`.
+    fn files_per_client(_client: &mut dyn FnMut(&[u8], u32)) {}
+}
+
+/// Filesystem usage in bytes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Usage {
+    pub used: u32,
+    pub total: u32,
+}
+
+/// Passes the storage report to `sink`: for the internal and then the
+/// external filesystem, used and total bytes (u32, big endian each,
+/// 0xFFFFFFFF if unknown) and wear (u8, 0xFF if unknown); followed by
+/// records of client name length (u8), client name and number of files
+/// (u32, big endian).
+pub(crate) fn report<P: StorageInfo>(mut sink: impl FnMut(&[u8])) {
+    for location in [Location::Internal, Location::External] {
+        let usage = P::usage(location).unwrap_or(Usage { used: u32::MAX, total: u32::MAX });
+        sink(&usage.used.to_be_bytes());
+        sink(&usage.total.to_be_bytes());
+        sink(&[P::wear(location).unwrap_or(0xFF)]);
+    }
+    P::files_per_client(&mut |name, files| {
+        let name = &name[..name.len().min(255)];
+        sink(&[name.len() as u8]);
+        sink(name);
+        sink(&files.to_be_bytes());
+    });
+}
@@ -0,0 +1,263 @@
+//! In-band firmware update.
+//!
+//! The host streams an image in chunks into a staging area provided by
+//! the platform; the device only reboots into the update once the whole
+//! image has been received, and its signature verified.
+//!
+//! Images older than the running firmware, or than a persisted minimum
+//! version, are rejected to prevent rollback to vulnerable firmware.
+use core::convert::TryInto;
+use sha2::{Digest, Sha256};
+use trussed::{
+    try_syscall,
+    Client as TrussedClient,
+    types::{KeySerialization, Location, Mechanism, Message, PathBuf, SignatureSerialization, StorageAttributes},
+};
+
+const MIN_VERSION_PATH: &str = "min-version";
+
+// UPDATE sub-commands (first payload byte over CTAPHID, P1 over APDU).
+// Without sub-command (zero over APDU) or with REBOOT_DESTRUCTIVE, UPDATE
+// reboots into the bootloader as the original firmware did; over CTAPHID,
+// those exact payloads reply without status byte. BOOTLOADER and
+// BOOTLOADER_DESTRUCTIVE do the same, with status byte.
+pub(crate) const REBOOT_DESTRUCTIVE: u8 = 0x01;
+pub(crate) const BOOTLOADER: u8 = 0x02;
+pub(crate) const BOOTLOADER_DESTRUCTIVE: u8 = 0x03;
+pub(crate) const BEGIN: u8 = 0x10;
+pub(crate) const WRITE: u8 = 0x11;
+pub(crate) const FINALIZE: u8 = 0x12;
+
+/// Staging area for incoming firmware images.
+///
+/// Typically a spare flash region, from which the image gets installed
+/// on `Reboot::reboot_to_staged_update`.
+pub trait Stage {
+    /// Prepares the staging area for an image of `length` bytes,
+    /// discarding anything staged previously.
+    fn begin(&mut self, length: u32) -> Result<(), StageError>;
+
+    /// Writes `data` at `offset` into the staging area.
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), StageError>;
+
+    /// Marks the staged image as complete.
+    fn finalize(&mut self) -> Result<(), StageError>;
+}
+
+/// The staging area failed to perform an operation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StageError;
+
+/// Update failure, its discriminant is reported as reason in the update status.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub(crate) enum Error {
+    /// The sub-command payload is malformed.
+    Malformed = 0x01,
+    /// No image is being received.
+    NotStarted = 0x02,
+    /// The chunk does not continue at the current offset,
+    /// or exceeds the announced length.
+    OutOfSequence = 0x03,
+    /// Finalized before the whole image was received.
+    Incomplete = 0x04,
+    /// The image signature does not verify against the update key.
+    Signature = 0x05,
+    /// The version is below the running firmware or the minimum version.
+    Version = 0x06,
+    /// The staging area failed.
+    Stage = 0x07,
+    /// The Trussed filesystem failed.
+    Storage = 0x08,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    Idle,
+    Receiving,
+    Verifying,
+    Staged,
+    Failed(Error),
+}
+
+pub(crate) struct Update<S: Stage> {
+    stage: S,
+    state: State,
+    /// Bytes received of the current or last image.
+    received: u32,
+    /// Announced length of the current or last image.
+    length: u32,
+    hasher: Sha256,
+    /// Ed25519 public key that images must be signed with.
+    key: [u8; 32],
+}
+
+impl<S: Stage> Update<S> {
+    pub fn new(stage: S, key: [u8; 32]) -> Self {
+        Self { stage, state: State::Idle, received: 0, length: 0, hasher: Sha256::new(), key }
+    }
+
+    /// Encodes the status of the current or last update: state, failure reason
+    /// (zero unless failed), bytes received and announced length (u32, big endian each).
+    ///
+    /// States are idle (0), receiving (1), verifying (2), staged (3) and failed (4).
+    /// Interrupted uploads can be resumed at the number of bytes received.
+    pub fn status(&self) -> [u8; 10] {
+        let (state, reason) = match self.state {
+            State::Idle => (0, 0),
+            State::Receiving => (1, 0),
+            State::Verifying => (2, 0),
+            State::Staged => (3, 0),
+            State::Failed(error) => (4, error as u8),
+        };
+        let mut status = [0u8; 10];
+        status[0] = state;
+        status[1] = reason;
+        status[2..6].copy_from_slice(&self.received.to_be_bytes());
+        status[6..].copy_from_slice(&self.length.to_be_bytes());
+        status
+    }
+
+    /// Starts receiving an image, payload is its length and version
+    /// (u32, big endian each).
+    ///
+    /// The version may be lower than neither the `running` version,
+    /// nor the persisted minimum version.
+    pub fn begin<T: TrussedClient>(&mut self, trussed: &mut T, running: u32, data: &[u8]) -> Result<(), Error> {
+        self.received = 0;
+        self.length = 0;
+        if data.len() != 8 {
+            return Err(self.fail(Error::Malformed));
+        }
+        let (length, version) = data.split_at(4);
+        let (length, version) = (be_u32(length)?, be_u32(version)?);
+        self.length = length;
+        if version < running || version < min_version(trussed) {
+            return Err(self.fail(Error::Version));
+        }
+
+        self.hasher.reset();
+        self.hasher.update(&version.to_be_bytes());
+        self.stage.begin(length).map_err(|_| self.fail(Error::Stage))?;
+        self.state = State::Receiving;
+        Ok(())
+    }
+
+    /// Stages a chunk, payload is its offset (u32, big endian) followed by the data.
+    ///
+    /// Chunks must be sent in order. Returns the offset expected next.
+    pub fn write(&mut self, data: &[u8]) -> Result<u32, Error> {
+        if self.state != State::Receiving {
+            return Err(Error::NotStarted);
+        }
+        if data.len() < 4 {
+            return Err(Error::Malformed);
+        }
+        let (offset, chunk) = data.split_at(4);
+        let offset = be_u32(offset)?;
+
+        let end = offset.checked_add(chunk.len() as u32).ok_or(Error::OutOfSequence)?;
+        if offset != self.received || end > self.length {
+            return Err(Error::OutOfSequence);
+        }
+
+        self.stage.write(offset, chunk).map_err(|_| self.fail(Error::Stage))?;
+        self.hasher.update(chunk);
+        self.received = end;
+        Ok(end)
+    }
+
+    /// Completes the upload, payload is the Ed25519 signature over the
+    /// SHA-256 digest of the version (u32, big endian) followed by the image.
+    ///
+    /// Succeeds only once the whole image was received and its signature
+    /// verifies against the update key; otherwise the image is discarded.
+    pub fn finalize<T: TrussedClient>(&mut self, trussed: &mut T, signature: &[u8]) -> Result<(), Error> {
+        if signature.len() != 64 {
+            return Err(Error::Malformed);
+        }
+        match self.state {
+            State::Receiving if self.received == self.length => {}
+            State::Receiving => return Err(Error::Incomplete),
+            _ => return Err(Error::NotStarted),
+        }
+
+        self.state = State::Verifying;
+        let digest = self.hasher.finalize_reset();
+        if !self.verify(trussed, &digest, signature) {
+            return Err(self.fail(Error::Signature));
+        }
+        self.stage.finalize().map_err(|_| self.fail(Error::Stage))?;
+        self.state = State::Staged;
+        Ok(())
+    }
+
+    fn fail(&mut self, error: Error) -> Error {
+        self.state = State::Failed(error);
+        error
+    }
+
+    fn verify<T: TrussedClient>(&self, trussed: &mut T, digest: &[u8], signature: &[u8]) -> bool {
+        let key = match try_syscall!(trussed.deserialize_key(
+            Mechanism::Ed255,
+            &self.key,
+            KeySerialization::Raw,
+            StorageAttributes::new().set_persistence(Location::Volatile),
+        )) {
+            Ok(reply) => reply.key,
+            Err(_) => return false,
+        };
+        let valid = try_syscall!(trussed.verify(
+            Mechanism::Ed255,
+            key,
+            digest,
+            signature,
+            SignatureSerialization::Raw,
+        ))
+        .map(|reply| reply.valid)
+        .unwrap_or(false);
+        try_syscall!(trussed.delete(key)).ok();
+        valid
+    }
+}
+
+/// Returns the persisted minimum firmware version, zero if never set.
+pub(crate) fn min_version<T: TrussedClient>(trussed: &mut T) -> u32 {
+    try_syscall!(trussed.read_file(Location::Internal, PathBuf::from(MIN_VERSION_PATH)))
+        .ok()
+        .and_then(|reply| be_u32(&reply.data).ok())
+        .unwrap_or(0)
+}
+
+/// Raises the persisted minimum firmware version, payload is the new
+/// minimum (u32, big endian).
+///
+/// The minimum can neither be lowered, nor be raised above the `running` version.
+pub(crate) fn raise_min_version<T: TrussedClient>(trussed: &mut T, running: u32, data: &[u8]) -> Result<u32, Error> {
+    let version = be_u32(data)?;
+    if version < min_version(trussed) || version > running {
+        return Err(Error::Version);
+    }
+    let serialized = Message::from_slice(&version.to_be_bytes()).map_err(|_| Error::Storage)?;
+    try_syscall!(trussed.write_file(Location::Internal, PathBuf::from(MIN_VERSION_PATH), serialized, None))
+        .map_err(|_| Error::Storage)?;
+    Ok(version)
+}
+
+fn be_u32(data: &[u8]) -> Result<u32, Error> {
+    data.try_into()
+        .map(u32::from_be_bytes)
+        .map_err(|_| Error::Malformed)
+}
+
+impl From<Error> for crate::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Malformed => crate::Error::Malformed,
+            Error::NotStarted | Error::OutOfSequence | Error::Incomplete => crate::Error::Sequence,
+            Error::Signature => crate::Error::Signature,
+            Error::Version => crate::Error::Invalid,
+            Error::Stage | Error::Storage => crate::Error::Storage,
+        }
+    }
+}
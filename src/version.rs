@@ -0,0 +1,27 @@
+//! Structured firmware version information.
+use serde::Serialize;
+
+// VERSION sub-command (first payload byte over CTAPHID, P1 over APDU)
+// requesting the CBOR encoded `VersionInfo` instead of the legacy number.
+pub(crate) const INFO: u8 = 0x01;
+
+// Upper bound for the CBOR encoding of `VersionInfo`.
+pub(crate) const INFO_SIZE: usize = 512;
+
+/// Version of the running firmware, reported by the VERSION command.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct VersionInfo {
+    /// Numeric version, reported to legacy hosts and used to prevent rollbacks.
+    pub number: u32,
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+    /// Pre-release tag, e.g. `rc.1`.
+    pub pre: Option<&'static str>,
+    /// Build time in seconds since the Unix epoch.
+    pub build_timestamp: u64,
+    /// Git commit hash the firmware was built from.
+    pub commit: &'static str,
+    /// Enabled cargo features.
+    pub features: &'static [&'static str],
+}
@@ -0,0 +1,119 @@
+#![cfg(feature = "client")]
+
+use std::{collections::VecDeque, io};
+use admin_app::client::{AdminClient, Apdu, Card, Error, AID};
+
+/// Card replaying canned responses, and recording the commands.
+///
+/// Once out of responses, fails as if removed if `removed`, else as if
+/// the transport broke.
+#[derive(Default)]
+struct Replay {
+    commands: Vec<Vec<u8>>,
+    responses: VecDeque<Vec<u8>>,
+    removed: bool,
+}
+
+impl Replay {
+    fn new(responses: &[&[u8]]) -> Self {
+        // SELECT succeeds
+        let mut replay = Self::default();
+        replay.responses.push_back(vec![0x90, 0x00]);
+        replay.responses.extend(responses.iter().map(|response| response.to_vec()));
+        replay
+    }
+}
+
+impl Card for Replay {
+    fn transmit(&mut self, command: &[u8]) -> io::Result<Vec<u8>> {
+        self.commands.push(command.to_vec());
+        self.responses.pop_front().ok_or_else(|| match self.removed {
+            true => io::Error::from(io::ErrorKind::ConnectionReset),
+            false => io::Error::from(io::ErrorKind::UnexpectedEof),
+        })
+    }
+}
+
+#[test]
+fn select() {
+    let apdu = Apdu::new(Replay::new(&[])).unwrap();
+    let commands = apdu.into_card().commands;
+    assert_eq!(commands[0][..5], [0x00, 0xA4, 0x04, 0x00, AID.len() as u8]);
+    assert_eq!(commands[0][5..5 + AID.len()], *AID);
+}
+
+#[test]
+fn command_chaining() {
+    let mut apdu = Apdu::new(Replay::new(&[&[0x90, 0x00], &[0x90, 0x00], &[0x01, 0x90, 0x00]])).unwrap();
+    let data = [0x5A; 600];
+    assert_eq!(apdu.transmit(0x80, 0x51, 0x11, 0x00, &data).unwrap(), [0x01]);
+
+    let commands = apdu.into_card().commands;
+    assert_eq!(commands.len(), 4);
+    assert_eq!(commands[1][..5], [0x90, 0x51, 0x11, 0x00, 0xFF]);
+    assert_eq!(commands[1].len(), 5 + 255 + 1);
+    assert_eq!(commands[2][..5], [0x90, 0x51, 0x11, 0x00, 0xFF]);
+    assert_eq!(commands[3][..5], [0x80, 0x51, 0x11, 0x00, 90]);
+    assert_eq!(commands[3].len(), 5 + 90 + 1);
+}
+
+#[test]
+fn chaining_aborts_on_error() {
+    let mut apdu = Apdu::new(Replay::new(&[&[0x69, 0x85]])).unwrap();
+    assert!(matches!(apdu.transmit(0x80, 0x51, 0x11, 0x00, &[0; 300]), Err(Error::Status(0x6985))));
+    assert_eq!(apdu.into_card().commands.len(), 2);
+}
+
+#[test]
+fn get_response() {
+    let mut apdu = Apdu::new(Replay::new(&[&[0x01, 0x02, 0x61, 0x02], &[0x03, 0x04, 0x90, 0x00]])).unwrap();
+    assert_eq!(apdu.transmit(0x80, 0x6A, 0x00, 0x00, &[]).unwrap(), [0x01, 0x02, 0x03, 0x04]);
+
+    let commands = apdu.into_card().commands;
+    assert_eq!(commands[1], [0x80, 0x6A, 0x00, 0x00, 0x00]);
+    assert_eq!(commands[2], [0x00, 0xC0, 0x00, 0x00, 0x00]);
+}
+
+#[test]
+fn proprietary_class() {
+    let apdu = Apdu::new(Replay::new(&[&[0x01, 0x02, 0x03, 0x00, 0x90, 0x00]])).unwrap();
+    let mut client = AdminClient::new(apdu);
+    assert_eq!(client.version().unwrap(), 0x0102_0300);
+
+    let commands = client.into_inner().into_card().commands;
+    assert_eq!(commands[1], [0x80, 0x61, 0x00, 0x00, 0x00]);
+}
+
+#[test]
+fn rng_length() {
+    let mut random = vec![0x42; 1024];
+    random.extend_from_slice(&[0x90, 0x00]);
+    let apdu = Apdu::new(Replay::new(&[&random, &[0x42, 0x42, 0x90, 0x00]])).unwrap();
+    let mut client = AdminClient::new(apdu);
+    assert_eq!(client.rng(1026).unwrap(), [0x42; 1026]);
+
+    let commands = client.into_inner().into_card().commands;
+    assert_eq!(commands[1][..4], [0x80, 0x60, 0x04, 0x00]);
+    assert_eq!(commands[2][..4], [0x80, 0x60, 0x00, 0x02]);
+}
+
+#[test]
+fn rng_short() {
+    let apdu = Apdu::new(Replay::new(&[&[0x42, 0x90, 0x00]])).unwrap();
+    let mut client = AdminClient::new(apdu);
+    assert!(matches!(client.rng(2), Err(Error::Protocol)));
+}
+
+#[test]
+fn reboot_disconnects() {
+    let mut card = Replay::new(&[]);
+    card.removed = true;
+    let mut client = AdminClient::new(Apdu::new(card).unwrap());
+    assert!(client.reboot().is_ok());
+}
+
+#[test]
+fn reboot_transport_error() {
+    let mut client = AdminClient::new(Apdu::new(Replay::new(&[])).unwrap());
+    assert!(matches!(client.reboot(), Err(Error::Io(_))));
+}
@@ -0,0 +1,225 @@
+use apdu_dispatch::app::Interface;
+use apdu_dispatch::iso7816::Status;
+use crate::common::{apdu, rebooted, select, with_device, Rebooted, DEVICE_UUID, DEVICE_VERSION};
+
+const UPDATE: u8 = 0x51;
+const RESET: u8 = 0x59;
+const RNG: u8 = 0x60;
+const VERSION: u8 = 0x61;
+const UUID: u8 = 0x62;
+const CONFIG_SET: u8 = 0x66;
+const PIN: u8 = 0x68;
+const VERIFY: u8 = 0x20;
+const GET_DATA: u8 = 0xCA;
+const PUT_DATA: u8 = 0xDA;
+
+#[test]
+fn version() {
+    with_device(|device| {
+        let version = apdu(device, Interface::Contact, &[0x00, VERSION, 0x00, 0x00]).unwrap();
+        assert_eq!(version, DEVICE_VERSION.number.to_be_bytes());
+    });
+}
+
+#[test]
+fn uuid() {
+    with_device(|device| {
+        let uuid = apdu(device, Interface::Contactless, &[0x00, UUID, 0x00, 0x00]).unwrap();
+        assert_eq!(uuid, DEVICE_UUID);
+    });
+}
+
+#[test]
+fn select_fci() {
+    with_device(|device| {
+        let fci = select(device);
+        assert_eq!(fci[0], 0x6F);
+        assert_eq!(usize::from(fci[1]), fci.len() - 2);
+        assert_eq!(fci[2..13], [0x84, 0x09, 0xA0, 0x00, 0x00, 0x08, 0x47, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(fci[13], 0xA5);
+        assert_eq!(fci[15..18], [0x80, 0x01, 0x01]);
+        assert_eq!(fci[18..20], [0x81, 0x04]);
+        assert_eq!(fci[20..24], DEVICE_VERSION.number.to_be_bytes());
+        assert_eq!(fci[24..26], [0x82, 0x10]);
+        assert_eq!(fci[26..42], DEVICE_UUID);
+        assert_eq!(fci[42], 0x83);
+        let instructions = &fci[44..];
+        assert_eq!(instructions.len(), usize::from(fci[43]));
+        assert!(instructions.contains(&VERSION));
+        // Without attestation key
+        assert!(!instructions.contains(&0x64));
+    });
+}
+
+#[test]
+fn select_hides_pin_protected_uuid() {
+    with_device(|device| {
+        apdu(device, Interface::Contact, &[0x00, PIN, 0x01, 0x00, 0x04, b'1', b'2', b'3', b'4']).unwrap();
+        let fci = select(device);
+        assert_eq!(fci[24], 0x83);
+    });
+}
+
+#[test]
+fn rng() {
+    with_device(|device| {
+        assert_eq!(apdu(device, Interface::Contact, &[0x00, RNG, 0x00, 0x00]).unwrap().len(), 57);
+        assert_eq!(apdu(device, Interface::Contact, &[0x00, RNG, 0x00, 0x00, 0x20]).unwrap().len(), 32);
+        assert_eq!(apdu(device, Interface::Contact, &[0x00, RNG, 0x01, 0x00]).unwrap().len(), 256);
+    });
+}
+
+#[test]
+fn update_contact_only() {
+    with_device(|device| {
+        let command = [0x00, UPDATE, 0x00, 0x00];
+        assert_eq!(apdu(device, Interface::Contactless, &command), Err(Status::ConditionsOfUseNotSatisfied));
+        let rebooted = rebooted(|| { apdu(device, Interface::Contact, &command).ok(); });
+        assert_eq!(rebooted, Some(Rebooted::FirmwareUpdate));
+    });
+}
+
+#[test]
+fn factory_reset_contact_only() {
+    with_device(|device| {
+        let command = [0x00, RESET, 0x00, 0x00];
+        let rebooted_contactless = rebooted(|| {
+            assert_eq!(apdu(device, Interface::Contactless, &command), Err(Status::ConditionsOfUseNotSatisfied));
+        });
+        assert_eq!(rebooted_contactless, None);
+        let rebooted = rebooted(|| { apdu(device, Interface::Contact, &command).ok(); });
+        assert_eq!(rebooted, Some(Rebooted::FactoryReset));
+    });
+}
+
+#[test]
+fn nfc_disabled() {
+    with_device(|device| {
+        apdu(device, Interface::Contact, &[0x00, CONFIG_SET, 0x03, 0x00, 0x01, 0x01]).unwrap();
+        let command = [0x00, UUID, 0x00, 0x00];
+        assert_eq!(apdu(device, Interface::Contactless, &command), Err(Status::ConditionsOfUseNotSatisfied));
+        assert_eq!(apdu(device, Interface::Contact, &command).unwrap(), DEVICE_UUID);
+    });
+}
+
+#[test]
+fn pin_protected() {
+    with_device(|device| {
+        let uuid = [0x00, UUID, 0x00, 0x00];
+        apdu(device, Interface::Contact, &[0x00, PIN, 0x01, 0x00, 0x04, b'1', b'2', b'3', b'4']).unwrap();
+        assert_eq!(apdu(device, Interface::Contact, &uuid).unwrap(), DEVICE_UUID);
+
+        select(device);
+        assert_eq!(apdu(device, Interface::Contact, &uuid), Err(Status::SecurityStatusNotSatisfied));
+        assert_eq!(
+            apdu(device, Interface::Contact, &[0x00, PIN, 0x03, 0x00, 0x04, b'0', b'0', b'0', b'0']),
+            Err(Status::RemainingRetries(7)),
+        );
+        apdu(device, Interface::Contact, &[0x00, PIN, 0x03, 0x00, 0x04, b'1', b'2', b'3', b'4']).unwrap();
+        assert_eq!(apdu(device, Interface::Contact, &uuid).unwrap(), DEVICE_UUID);
+    });
+}
+
+#[test]
+fn get_data() {
+    with_device(|device| {
+        let uuid = apdu(device, Interface::Contact, &[0x00, GET_DATA, 0x01, 0x00]).unwrap();
+        assert_eq!(uuid, DEVICE_UUID);
+        let version = apdu(device, Interface::Contact, &[0x00, GET_DATA, 0x01, 0x01]).unwrap();
+        assert_eq!(version, DEVICE_VERSION.number.to_be_bytes());
+        assert_eq!(
+            apdu(device, Interface::Contact, &[0x00, GET_DATA, 0x7F, 0x00]),
+            Err(Status::FunctionNotSupported),
+        );
+    });
+}
+
+#[test]
+fn put_data() {
+    with_device(|device| {
+        apdu(device, Interface::Contact, &[0x00, PUT_DATA, 0x02, 0x02, 0x04, 0x00, 0x00, 0x01, 0xF4]).unwrap();
+        let wink_duration = apdu(device, Interface::Contact, &[0x00, GET_DATA, 0x02, 0x02]).unwrap();
+        assert_eq!(wink_duration, 500u32.to_be_bytes());
+        assert_eq!(
+            apdu(device, Interface::Contact, &[0x00, PUT_DATA, 0x01, 0x00, 0x01, 0x00]),
+            Err(Status::FunctionNotSupported),
+        );
+    });
+}
+
+#[test]
+fn verify() {
+    with_device(|device| {
+        apdu(device, Interface::Contact, &[0x00, PIN, 0x01, 0x00, 0x04, b'1', b'2', b'3', b'4']).unwrap();
+        select(device);
+        let uuid = [0x00, GET_DATA, 0x01, 0x00];
+        assert_eq!(apdu(device, Interface::Contact, &uuid), Err(Status::SecurityStatusNotSatisfied));
+        apdu(device, Interface::Contact, &[0x00, VERIFY, 0x00, 0x80, 0x04, b'1', b'2', b'3', b'4']).unwrap();
+        assert_eq!(apdu(device, Interface::Contact, &uuid).unwrap(), DEVICE_UUID);
+    });
+}
+
+#[test]
+fn verify_status() {
+    with_device(|device| {
+        let status = [0x00, VERIFY, 0x00, 0x80];
+        // Nothing to verify without PIN
+        apdu(device, Interface::Contact, &status).unwrap();
+
+        apdu(device, Interface::Contact, &[0x00, PIN, 0x01, 0x00, 0x04, b'1', b'2', b'3', b'4']).unwrap();
+        apdu(device, Interface::Contact, &status).unwrap();
+        select(device);
+        // Probing keeps the retries
+        for _ in 0..10 {
+            assert_eq!(apdu(device, Interface::Contact, &status), Err(Status::RemainingRetries(8)));
+        }
+
+        apdu(device, Interface::Contact, &[0x00, VERIFY, 0x00, 0x80, 0x04, b'1', b'2', b'3', b'4']).unwrap();
+        apdu(device, Interface::Contact, &status).unwrap();
+        apdu(device, Interface::Contact, &[0x00, VERIFY, 0xFF, 0x80]).unwrap();
+        assert_eq!(apdu(device, Interface::Contact, &status), Err(Status::RemainingRetries(8)));
+    });
+}
+
+#[test]
+fn verify_reference() {
+    with_device(|device| {
+        let pin = [0x04, b'1', b'2', b'3', b'4'];
+        apdu(device, Interface::Contact, &[&[0x00, PIN, 0x01, 0x00][..], &pin].concat()).unwrap();
+        assert_eq!(
+            apdu(device, Interface::Contact, &[&[0x00, VERIFY, 0x00, 0x81][..], &pin].concat()),
+            Err(Status::IncorrectP1OrP2Parameter),
+        );
+        assert_eq!(
+            apdu(device, Interface::Contact, &[&[0x00, VERIFY, 0x01, 0x80][..], &pin].concat()),
+            Err(Status::IncorrectP1OrP2Parameter),
+        );
+        assert_eq!(
+            apdu(device, Interface::Contact, &[&[0x00, VERIFY, 0xFF, 0x80][..], &pin].concat()),
+            Err(Status::WrongLength),
+        );
+    });
+}
+
+#[test]
+fn legacy_instructions() {
+    with_device(|device| {
+        apdu(device, Interface::Contact, &[0x00, PUT_DATA, 0x02, 0x04, 0x01, 0x00]).unwrap();
+        assert_eq!(
+            apdu(device, Interface::Contact, &[0x00, UUID, 0x00, 0x00]),
+            Err(Status::InstructionNotSupportedOrInvalid),
+        );
+        assert_eq!(apdu(device, Interface::Contact, &[0x80, UUID, 0x00, 0x00]).unwrap(), DEVICE_UUID);
+        assert_eq!(apdu(device, Interface::Contact, &[0x00, GET_DATA, 0x01, 0x00]).unwrap(), DEVICE_UUID);
+    });
+}
+
+#[test]
+fn unknown_instruction() {
+    with_device(|device| {
+        assert_eq!(
+            apdu(device, Interface::Contact, &[0x00, 0x01, 0x00, 0x00]),
+            Err(Status::InstructionNotSupportedOrInvalid),
+        );
+    });
+}
@@ -0,0 +1,106 @@
+use admin_app::Error;
+use crate::common::{assert_signed, hid, with_attested_device, with_device, Device};
+use ctaphid_dispatch::command::VendorCommand;
+use sha2::{Digest, Sha256};
+
+const CONFIRM: VendorCommand = VendorCommand::H56;
+const CONFIG_SET: VendorCommand = VendorCommand::H66;
+const PIN: VendorCommand = VendorCommand::H68;
+const READ_LOG: VendorCommand = VendorCommand::H6A;
+const LOG_HEAD: VendorCommand = VendorCommand::H6B;
+
+// Events.
+const CONFIRMED: u8 = 0x06;
+const CONFIG_CHANGED: u8 = 0x09;
+const PIN_CHANGED: u8 = 0x0A;
+const PIN_FAILED: u8 = 0x0B;
+
+const WINK_DURATION: [u8; 5] = [0x02, 0x00, 0x00, 0x01, 0xF4];
+
+/// Reads a page of the log starting at sequence number `from`, returning
+/// the hash preceding it and its entries.
+fn read_log(device: &mut Device, from: Option<u32>) -> ([u8; 32], Vec<[u8; 9]>) {
+    let payload = from.map(|from| from.to_be_bytes().to_vec()).unwrap_or_default();
+    let page = hid(device, READ_LOG, &payload).unwrap();
+    let (base, entries) = page.split_at(32);
+    assert_eq!(entries.len() % 9, 0);
+    let entries = entries.chunks_exact(9).map(|entry| entry.try_into().unwrap()).collect();
+    (base.try_into().unwrap(), entries)
+}
+
+fn chain(base: [u8; 32], entries: &[[u8; 9]]) -> [u8; 32] {
+    entries.iter().fold(base, |head, entry| Sha256::new().chain(head).chain(entry).finalize().into())
+}
+
+fn entry(sequence: u32, event: u8, detail: u32) -> [u8; 9] {
+    let mut entry = [0u8; 9];
+    entry[..4].copy_from_slice(&sequence.to_be_bytes());
+    entry[4] = event;
+    entry[5..].copy_from_slice(&detail.to_be_bytes());
+    entry
+}
+
+#[test]
+fn chain_verifies() {
+    with_device(|device| {
+        hid(device, CONFIG_SET, &WINK_DURATION).unwrap();
+        hid(device, PIN, &[0x01, b'1', b'2', b'3', b'4']).unwrap();
+        assert_eq!(hid(device, PIN, &[0x03, b'0', b'0', b'0', b'0']), Err(Error::WrongPin(7)));
+        hid(device, CONFIRM, &[]).unwrap();
+
+        let (base, entries) = read_log(device, None);
+        assert_eq!(base, [0; 32]);
+        assert_eq!(entries, [
+            entry(0, CONFIG_CHANGED, 0x02),
+            entry(1, PIN_CHANGED, 0),
+            entry(2, PIN_FAILED, 7),
+            entry(3, CONFIRMED, 0),
+        ]);
+
+        let head = hid(device, LOG_HEAD, &[]).unwrap();
+        assert_eq!(head[..32], chain(base, &entries));
+        assert_eq!(head[32..], 4u32.to_be_bytes());
+
+        // A later page continues the chain
+        let (later_base, later_entries) = read_log(device, Some(2));
+        assert_eq!(later_base, chain(base, &entries[..2]));
+        assert_eq!(later_entries, entries[2..]);
+    });
+}
+
+#[test]
+fn oldest_entries_dropped() {
+    with_device(|device| {
+        for _ in 0..70 {
+            hid(device, CONFIG_SET, &WINK_DURATION).unwrap();
+        }
+
+        // The 64 entries kept, in pages of 16
+        let (base, mut entries) = read_log(device, None);
+        assert_ne!(base, [0; 32]);
+        while entries.len() < 64 {
+            let next = u32::from_be_bytes(entries.last().unwrap()[..4].try_into().unwrap()) + 1;
+            let (page_base, page) = read_log(device, Some(next));
+            assert_eq!(page_base, chain(base, &entries));
+            assert!(!page.is_empty());
+            entries.extend(page);
+        }
+        assert_eq!(entries[0], entry(6, CONFIG_CHANGED, 0x02));
+        assert_eq!(entries[63], entry(69, CONFIG_CHANGED, 0x02));
+
+        let head = hid(device, LOG_HEAD, &[]).unwrap();
+        assert_eq!(head[..32], chain(base, &entries));
+        assert_eq!(head[32..], 70u32.to_be_bytes());
+    });
+}
+
+#[test]
+fn head_signed() {
+    with_attested_device(|device, public_key| {
+        hid(device, CONFIG_SET, &WINK_DURATION).unwrap();
+        let response = hid(device, LOG_HEAD, &[]).unwrap();
+        let (head, signature) = response.split_at(36);
+        assert_eq!(head[32..], 1u32.to_be_bytes());
+        assert_signed(public_key, b"admin-app log head\0", head, signature);
+    });
+}
@@ -0,0 +1,216 @@
+use aes::Aes256;
+use apdu_dispatch::app::Interface;
+use apdu_dispatch::iso7816::Status;
+use block_modes::{block_padding::NoPadding, BlockMode, Cbc};
+use crate::common::{
+    apdu, assert_signed, select, with_attested_device, with_device, Device, DEVICE_UUID, DEVICE_VERSION,
+};
+use hmac::{Hmac, Mac, NewMac};
+use p256::{ecdh, elliptic_curve::sec1::ToEncodedPoint, PublicKey, SecretKey};
+use sha2::{Digest, Sha256};
+
+const RNG: u8 = 0x60;
+const VERSION: u8 = 0x61;
+const UUID: u8 = 0x62;
+const CONFIG_GET: u8 = 0x65;
+const CONFIG_SET: u8 = 0x66;
+const SECURE_CHANNEL: u8 = 0x69;
+
+// Proprietary class with secure messaging indication.
+const SECURE_CLASS: u8 = 0x8C;
+
+type Aes256Cbc = Cbc<Aes256, NoPadding>;
+
+/// Host end of a secure channel.
+struct Host {
+    enc: [u8; 32],
+    mac: [u8; 32],
+    counter: u32,
+    /// Host and device public keys, and their signature if the device is attested.
+    keys: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl Host {
+    /// Opens a channel over `interface`.
+    fn open(device: &mut Device, interface: Interface) -> Result<Self, Status> {
+        let secret = SecretKey::from_slice(&[0x5A; 32]).unwrap();
+        let public_key = secret.public_key().to_encoded_point(false);
+        let mut command = vec![0x80, SECURE_CHANNEL, 0x00, 0x00, 64];
+        command.extend_from_slice(&public_key.as_bytes()[1..]);
+
+        let response = apdu(device, interface, &command)?;
+        let (device_key, signature) = response.split_at(64);
+        let keys = [&public_key.as_bytes()[1..], device_key].concat();
+        let device_key = PublicKey::from_sec1_bytes(&[&[0x04][..], device_key].concat()).unwrap();
+        let shared_secret = ecdh::diffie_hellman(secret.to_nonzero_scalar(), device_key.as_affine());
+
+        let enc: [u8; 32] = Sha256::digest(shared_secret.raw_secret_bytes()).into();
+        let mac: [u8; 32] = Sha256::digest(&enc).into();
+        Ok(Self { enc, mac, counter: 0, keys, signature: signature.to_vec() })
+    }
+
+    /// Wraps the command with header `INS, P1, P2` and `data`.
+    fn wrap(&mut self, header: [u8; 3], data: &[u8]) -> Vec<u8> {
+        self.counter += 1;
+        let ciphertext = self.encrypt(0x00, data);
+        let mac = self.authenticate(&[&header[..], &ciphertext].concat());
+
+        let mut command = vec![SECURE_CLASS];
+        command.extend_from_slice(&header);
+        command.push((ciphertext.len() + 16) as u8);
+        command.extend_from_slice(&ciphertext);
+        command.extend_from_slice(&mac);
+        command
+    }
+
+    /// Verifies and decrypts the response to the last command.
+    fn unwrap(&self, response: &[u8]) -> Vec<u8> {
+        let (ciphertext, mac) = response.split_at(response.len() - 16);
+        assert_eq!(mac, self.authenticate(ciphertext));
+        let plaintext = Aes256Cbc::new_from_slices(&self.enc, &[0; 16]).unwrap().decrypt_vec(ciphertext).unwrap();
+        assert_eq!(plaintext[..16], counter_block(0x01, self.counter));
+        let end = plaintext.iter().rposition(|&byte| byte != 0).unwrap();
+        assert_eq!(plaintext[end], 0x80);
+        plaintext[16..end].to_vec()
+    }
+
+    fn encrypt(&self, direction: u8, data: &[u8]) -> Vec<u8> {
+        let mut plaintext = counter_block(direction, self.counter).to_vec();
+        plaintext.extend_from_slice(data);
+        plaintext.push(0x80);
+        plaintext.resize(plaintext.len().next_multiple_of(16), 0x00);
+        Aes256Cbc::new_from_slices(&self.enc, &[0; 16]).unwrap().encrypt_vec(&plaintext)
+    }
+
+    fn authenticate(&self, data: &[u8]) -> [u8; 16] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.mac).unwrap();
+        mac.update(data);
+        mac.finalize().into_bytes()[..16].try_into().unwrap()
+    }
+}
+
+fn counter_block(direction: u8, counter: u32) -> [u8; 16] {
+    let mut block = [0u8; 16];
+    block[0] = direction;
+    block[12..].copy_from_slice(&counter.to_be_bytes());
+    block
+}
+
+#[test]
+fn round_trip() {
+    with_device(|device| {
+        select(device);
+        let mut host = Host::open(device, Interface::Contact).unwrap();
+
+        let command = host.wrap([UUID, 0x00, 0x00], &[]);
+        let response = apdu(device, Interface::Contact, &command).unwrap();
+        assert_eq!(host.unwrap(&response), DEVICE_UUID);
+
+        let command = host.wrap([VERSION, 0x00, 0x00], &[]);
+        let response = apdu(device, Interface::Contact, &command).unwrap();
+        assert_eq!(host.unwrap(&response), DEVICE_VERSION.number.to_be_bytes());
+
+        // With command data
+        let command = host.wrap([CONFIG_SET, 0x02, 0x00], &500u32.to_be_bytes());
+        let response = apdu(device, Interface::Contact, &command).unwrap();
+        assert!(host.unwrap(&response).is_empty());
+        let command = host.wrap([CONFIG_GET, 0x02, 0x00], &[]);
+        let response = apdu(device, Interface::Contact, &command).unwrap();
+        assert_eq!(host.unwrap(&response), 500u32.to_be_bytes());
+    });
+}
+
+#[test]
+fn attested() {
+    with_attested_device(|device, public_key| {
+        select(device);
+        let mut host = Host::open(device, Interface::Contact).unwrap();
+        assert_signed(public_key, b"admin-app channel\0", &host.keys, &host.signature);
+
+        let command = host.wrap([UUID, 0x00, 0x00], &[]);
+        let response = apdu(device, Interface::Contact, &command).unwrap();
+        assert_eq!(host.unwrap(&response), DEVICE_UUID);
+    });
+    with_device(|device| {
+        select(device);
+        assert!(Host::open(device, Interface::Contact).unwrap().signature.is_empty());
+    });
+}
+
+#[test]
+fn oversize_reply() {
+    with_device(|device| {
+        select(device);
+        let mut host = Host::open(device, Interface::Contact).unwrap();
+
+        // The longest reply that can be wrapped
+        let command = host.wrap([RNG, 0x03, 0xD0], &[]);
+        let response = apdu(device, Interface::Contact, &command).unwrap();
+        assert_eq!(host.unwrap(&response).len(), 976);
+
+        // Longer replies are refused before executing the command
+        let command = host.wrap([RNG, 0x04, 0x00], &[]);
+        assert_eq!(apdu(device, Interface::Contact, &command), Err(Status::WrongLength));
+
+        // The channel stays open
+        let command = host.wrap([UUID, 0x00, 0x00], &[]);
+        let response = apdu(device, Interface::Contact, &command).unwrap();
+        assert_eq!(host.unwrap(&response), DEVICE_UUID);
+    });
+}
+
+#[test]
+fn replay_rejected() {
+    with_device(|device| {
+        select(device);
+        let mut host = Host::open(device, Interface::Contact).unwrap();
+        let command = host.wrap([UUID, 0x00, 0x00], &[]);
+        apdu(device, Interface::Contact, &command).unwrap();
+        assert_eq!(apdu(device, Interface::Contact, &command), Err(Status::SecurityStatusNotSatisfied));
+    });
+}
+
+#[test]
+fn mac_failure() {
+    with_device(|device| {
+        select(device);
+        let mut host = Host::open(device, Interface::Contact).unwrap();
+        let mut command = host.wrap([UUID, 0x00, 0x00], &[]);
+        *command.last_mut().unwrap() ^= 0x01;
+        assert_eq!(apdu(device, Interface::Contact, &command), Err(Status::SecurityStatusNotSatisfied));
+
+        // The channel was closed, and can be reopened
+        let mut host = Host::open(device, Interface::Contact).unwrap();
+        let command = host.wrap([UUID, 0x00, 0x00], &[]);
+        let response = apdu(device, Interface::Contact, &command).unwrap();
+        assert_eq!(host.unwrap(&response), DEVICE_UUID);
+    });
+}
+
+#[test]
+fn nfc_secure() {
+    with_device(|device| {
+        apdu(device, Interface::Contact, &[0x80, CONFIG_SET, 0x03, 0x00, 0x01, 0x02]).unwrap();
+        select(device);
+        let uuid = [0x80, UUID, 0x00, 0x00];
+        assert_eq!(apdu(device, Interface::Contactless, &uuid), Err(Status::SecurityStatusNotSatisfied));
+
+        let mut host = Host::open(device, Interface::Contactless).unwrap();
+        let command = host.wrap([UUID, 0x00, 0x00], &[]);
+        let response = apdu(device, Interface::Contactless, &command).unwrap();
+        assert_eq!(host.unwrap(&response), DEVICE_UUID);
+    });
+}
+
+#[test]
+fn nfc_disabled() {
+    with_device(|device| {
+        apdu(device, Interface::Contact, &[0x80, CONFIG_SET, 0x03, 0x00, 0x01, 0x01]).unwrap();
+        select(device);
+        assert_eq!(
+            Host::open(device, Interface::Contactless).err(),
+            Some(Status::ConditionsOfUseNotSatisfied),
+        );
+    });
+}
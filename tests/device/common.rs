@@ -0,0 +1,313 @@
+//! Software-simulated device: the admin app on a Trussed virtual client,
+//! with a platform that records reboots and slot operations.
+//!
+//! As `Reboot` diverges, reboots unwind with a `Rebooted` payload,
+//! which `rebooted` catches.
+use std::{
+    cell::{Cell, RefCell},
+    panic::{self, AssertUnwindSafe},
+    sync::Once,
+};
+use admin_app::{
+    App, AppInfo, Attestation, Error, Keepalive, KeepaliveStatus, Reboot, Slot, SlotError, Stage, StageError,
+    StorageInfo, Usage, VersionInfo,
+};
+use apdu_dispatch::{app as apdu, response, Command};
+use apdu_dispatch::iso7816::Status;
+use ctaphid_dispatch::app::{self as hid, Command as HidCommand, Message};
+use ctaphid_dispatch::command::VendorCommand;
+use ed25519_dalek::{Signer, SigningKey};
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+use trussed::{
+    syscall,
+    client::{CertificateClient, CryptoClient},
+    types::{KeySerialization, Location, Mechanism, StorageAttributes},
+    virt::{self, Ram},
+};
+
+pub type Device = App<virt::Client<Ram>, Platform, Staging>;
+
+pub const DEVICE_UUID: [u8; 16] = *b"simulated-device";
+
+pub const DEVICE_VERSION: VersionInfo = VersionInfo {
+    number: 0x0102_0300,
+    major: 1,
+    minor: 2,
+    patch: 3,
+    pre: None,
+    build_timestamp: 0,
+    commit: "0000000",
+    features: &[],
+};
+
+/// Seed of the Ed25519 key firmware images are signed with.
+const UPDATE_KEY: [u8; 32] = *b"simulated-device-update-key-seed";
+
+/// Applications installed alongside the admin app.
+pub const APPS: &[AppInfo] = &[
+    AppInfo { name: "fido", version: "1.0.0", aid: None, commands: &[HidCommand::Msg, HidCommand::Cbor] },
+    AppInfo { name: "piv", version: "0.2.1", aid: Some(&[0xA0, 0x00, 0x00, 0x03, 0x08]), commands: &[] },
+];
+
+/// Attestation certificate of attested devices, the app passes it through as is.
+pub const CERTIFICATE: &[u8] = b"\x30\x0Esimulated cert";
+
+/// Reboot requested through `Reboot`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Rebooted {
+    Normal,
+    FirmwareUpdate,
+    FirmwareUpdateDestructive,
+    StagedUpdate,
+    FactoryReset,
+}
+
+thread_local! {
+    static CONFIRMED: Cell<bool> = Cell::new(false);
+    static REVERTED: Cell<bool> = Cell::new(false);
+    static STAGED: RefCell<Option<Vec<u8>>> = RefCell::new(None);
+    static KEEPALIVES: RefCell<Vec<KeepaliveStatus>> = RefCell::new(Vec::new());
+    static CANCELLED: Cell<bool> = Cell::new(false);
+    static FACTORY_RESETS: Cell<u32> = Cell::new(0);
+}
+
+pub struct Platform;
+
+impl Reboot for Platform {
+    fn reboot() -> ! {
+        panic::panic_any(Rebooted::Normal)
+    }
+
+    fn reboot_to_firmware_update() -> ! {
+        panic::panic_any(Rebooted::FirmwareUpdate)
+    }
+
+    fn reboot_to_firmware_update_destructive() -> ! {
+        panic::panic_any(Rebooted::FirmwareUpdateDestructive)
+    }
+
+    fn reboot_to_staged_update() -> ! {
+        panic::panic_any(Rebooted::StagedUpdate)
+    }
+
+    fn reboot_to_factory_reset() -> ! {
+        panic::panic_any(Rebooted::FactoryReset)
+    }
+
+    fn factory_resets() -> u32 {
+        FACTORY_RESETS.with(Cell::get)
+    }
+
+    fn active_slot() -> Option<Slot> {
+        Some(Slot::A)
+    }
+
+    fn pending_slot() -> Option<Slot> {
+        REVERTED.with(Cell::get).then_some(Slot::B)
+    }
+
+    fn is_confirmed() -> bool {
+        CONFIRMED.with(Cell::get)
+    }
+
+    fn confirm() -> Result<(), SlotError> {
+        CONFIRMED.with(|confirmed| confirmed.set(true));
+        Ok(())
+    }
+
+    fn revert() -> Result<(), SlotError> {
+        REVERTED.with(|reverted| reverted.set(true));
+        Ok(())
+    }
+}
+
+// Fixed figures: a half-used internal filesystem, an unknown external one.
+impl StorageInfo for Platform {
+    fn usage(location: Location) -> Option<Usage> {
+        (location == Location::Internal).then_some(Usage { used: 0x8000, total: 0x1_0000 })
+    }
+
+    fn wear(location: Location) -> Option<u8> {
+        (location == Location::Internal).then_some(3)
+    }
+
+    fn files_per_client(client: &mut dyn FnMut(&[u8], u32)) {
+        client(b"admin", 2);
+        client(b"fido", 5);
+    }
+}
+
+impl Keepalive for Platform {
+    fn keepalive(status: KeepaliveStatus) {
+        KEEPALIVES.with(|keepalives| keepalives.borrow_mut().push(status));
+    }
+
+    fn cancelled() -> bool {
+        CANCELLED.with(Cell::get)
+    }
+}
+
+/// Returns and clears the keepalives reported so far.
+pub fn keepalives() -> Vec<KeepaliveStatus> {
+    KEEPALIVES.with(|keepalives| keepalives.take())
+}
+
+/// Cancels the commands that follow, as if the host sent CTAPHID_CANCEL.
+pub fn cancel() {
+    CANCELLED.with(|cancelled| cancelled.set(true));
+}
+
+/// Sets the number of factory resets the platform counted.
+pub fn set_factory_resets(count: u32) {
+    FACTORY_RESETS.with(|resets| resets.set(count));
+}
+
+/// Staging area in memory, see `staged`.
+pub struct Staging;
+
+impl Stage for Staging {
+    fn begin(&mut self, length: u32) -> Result<(), StageError> {
+        STAGED.with(|staged| *staged.borrow_mut() = Some(Vec::with_capacity(length as usize)));
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), StageError> {
+        STAGED.with(|staged| {
+            let mut staged = staged.borrow_mut();
+            let image = staged.as_mut().ok_or(StageError)?;
+            if image.len() != offset as usize {
+                return Err(StageError);
+            }
+            image.extend_from_slice(data);
+            Ok(())
+        })
+    }
+
+    fn finalize(&mut self) -> Result<(), StageError> {
+        Ok(())
+    }
+}
+
+/// Returns the image in the staging area, if an upload was begun.
+pub fn staged() -> Option<Vec<u8>> {
+    STAGED.with(|staged| staged.borrow().clone())
+}
+
+/// Runs `f` on a fresh device, with empty storage and unconfirmed slot A active.
+pub fn with_device<R>(f: impl FnOnce(&mut Device) -> R) -> R {
+    reset_platform();
+    virt::with_ram_client("admin", |client| {
+        let mut device = App::new(client, DEVICE_UUID, DEVICE_VERSION, Staging, update_key(), None, APPS);
+        f(&mut device)
+    })
+}
+
+/// Runs `f` on a fresh device as `with_device`, with an attestation key and
+/// `CERTIFICATE` provisioned, passing the attestation public key to `f`.
+pub fn with_attested_device<R>(f: impl FnOnce(&mut Device, &VerifyingKey) -> R) -> R {
+    reset_platform();
+    virt::with_ram_client("admin", |mut client| {
+        let (attestation, public_key) = provision(&mut client);
+        let mut device = App::new(client, DEVICE_UUID, DEVICE_VERSION, Staging, update_key(), Some(attestation), APPS);
+        f(&mut device, &public_key)
+    })
+}
+
+fn reset_platform() {
+    silence_reboots();
+    CONFIRMED.with(|confirmed| confirmed.set(false));
+    REVERTED.with(|reverted| reverted.set(false));
+    STAGED.with(|staged| *staged.borrow_mut() = None);
+    KEEPALIVES.with(|keepalives| keepalives.borrow_mut().clear());
+    CANCELLED.with(|cancelled| cancelled.set(false));
+    FACTORY_RESETS.with(|resets| resets.set(0));
+}
+
+fn update_key() -> [u8; 32] {
+    SigningKey::from_bytes(&UPDATE_KEY).verifying_key().to_bytes()
+}
+
+/// Generates a P-256 attestation key and stores `CERTIFICATE`, as done when
+/// provisioning a device.
+fn provision(client: &mut virt::Client<Ram>) -> (Attestation, VerifyingKey) {
+    let internal = StorageAttributes::new().set_persistence(Location::Internal);
+    let volatile = StorageAttributes::new().set_persistence(Location::Volatile);
+    let key = syscall!(client.generate_key(Mechanism::P256, internal)).key;
+    let public_key = syscall!(client.derive_key(Mechanism::P256, key, None, volatile)).key;
+    let raw = syscall!(client.serialize_key(Mechanism::P256, public_key, KeySerialization::Raw)).serialized_key;
+    let certificate = syscall!(client.write_certificate(Location::Internal, CERTIFICATE)).id;
+
+    let public_key = VerifyingKey::from_sec1_bytes(&[&[0x04][..], &raw].concat()).unwrap();
+    (Attestation { key, certificate }, public_key)
+}
+
+/// Asserts that `signature`, DER encoded, was made by `key` over `label || message`.
+pub fn assert_signed(key: &VerifyingKey, label: &[u8], message: &[u8], signature: &[u8]) {
+    let signature = Signature::from_der(signature).unwrap();
+    assert!(key.verify(&[label, message].concat(), &signature).is_ok());
+}
+
+/// Signs the firmware image `image` of version `version` with the update key.
+pub fn sign_image(version: u32, image: &[u8]) -> [u8; 64] {
+    let mut hasher = Sha256::new();
+    hasher.update(version.to_be_bytes());
+    hasher.update(image);
+    SigningKey::from_bytes(&UPDATE_KEY).sign(&hasher.finalize()).to_bytes()
+}
+
+/// Sends a vendor command over CTAPHID, returning the response data after the status byte.
+pub fn hid(device: &mut Device, command: VendorCommand, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let input = Message::from_slice(data).unwrap();
+    let mut response = Message::new();
+    hid::App::call(device, HidCommand::Vendor(command), &input, &mut response).unwrap();
+    Error::check(&response).map(<[u8]>::to_vec)
+}
+
+/// Sends a vendor command old hosts send over CTAPHID, replying without status byte.
+pub fn hid_legacy(device: &mut Device, command: VendorCommand, data: &[u8]) -> Result<Vec<u8>, hid::Error> {
+    let input = Message::from_slice(data).unwrap();
+    let mut response = Message::new();
+    hid::App::call(device, HidCommand::Vendor(command), &input, &mut response)?;
+    Ok(response.to_vec())
+}
+
+/// Selects the app over APDU, returning the FCI template.
+pub fn select(device: &mut Device) -> Vec<u8> {
+    let command = Command::try_from(&[0x00, 0xA4, 0x04, 0x00][..]).unwrap();
+    let mut reply = response::Data::new();
+    apdu::App::select(device, &command, &mut reply).unwrap();
+    reply.to_vec()
+}
+
+/// Sends a command APDU over `interface`.
+pub fn apdu(device: &mut Device, interface: apdu::Interface, command: &[u8]) -> Result<Vec<u8>, Status> {
+    let command = Command::try_from(command).unwrap();
+    let mut reply = response::Data::new();
+    apdu::App::call(device, interface, &command, &mut reply)?;
+    Ok(reply.to_vec())
+}
+
+/// Runs `f`, returning the reboot it requested, if any.
+pub fn rebooted(f: impl FnOnce()) -> Option<Rebooted> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(()) => None,
+        Err(payload) => match payload.downcast::<Rebooted>() {
+            Ok(rebooted) => Some(*rebooted),
+            Err(payload) => panic::resume_unwind(payload),
+        },
+    }
+}
+
+/// Keeps reboots out of the test output.
+fn silence_reboots() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let default = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if info.payload().downcast_ref::<Rebooted>().is_none() {
+                default(info);
+            }
+        }));
+    });
+}
@@ -0,0 +1,100 @@
+use apdu_dispatch::dispatch::ApduDispatch;
+use apdu_dispatch::interchanges::{Contact, Contactless, Data};
+use crate::common::{rebooted, sign_image, staged, with_device, Device, Rebooted, DEVICE_VERSION};
+use interchange::{Interchange, Requester};
+
+const AID: [u8; 9] = [0xA0, 0x00, 0x00, 0x08, 0x47, 0x00, 0x00, 0x00, 0x01];
+const UPDATE: u8 = 0x51;
+const RNG: u8 = 0x60;
+const GET_RESPONSE: u8 = 0xC0;
+
+const BEGIN: u8 = 0x10;
+const WRITE: u8 = 0x11;
+const FINALIZE: u8 = 0x12;
+
+/// Contact interface of the dispatcher, as a reader would drive it.
+struct Reader {
+    dispatch: ApduDispatch,
+    requester: Requester<Contact>,
+}
+
+impl Reader {
+    fn new() -> Self {
+        // Interchanges can be claimed once per process
+        let (requester, contact) = Contact::claim().unwrap();
+        let (_, contactless) = Contactless::claim().unwrap();
+        Self { dispatch: ApduDispatch::new(contact, contactless), requester }
+    }
+
+    /// Sends a command APDU, returning the response data and status word.
+    fn exchange(&mut self, device: &mut Device, command: &[u8]) -> (Vec<u8>, u16) {
+        self.requester.request(&Data::from_slice(command).unwrap()).unwrap();
+        self.dispatch.poll(&mut [device]);
+        let mut response = self.requester.take_response().unwrap().to_vec();
+        let status = response.split_off(response.len() - 2);
+        (response, u16::from_be_bytes([status[0], status[1]]))
+    }
+
+    /// Sends a command as short APDUs, chaining its data in pieces of up to
+    /// 255 bytes, and collects the response announced with 61xx.
+    fn transmit(&mut self, device: &mut Device, header: [u8; 4], data: &[u8]) -> (Vec<u8>, u16) {
+        let mut chunks = data.chunks(255).peekable();
+        let (mut response, mut status) = loop {
+            let chunk = chunks.next().unwrap_or(&[]);
+            let mut command = header.to_vec();
+            if chunks.peek().is_some() {
+                command[0] |= 0x10;
+            }
+            if !chunk.is_empty() {
+                command.push(chunk.len() as u8);
+                command.extend_from_slice(chunk);
+            }
+            command.push(0x00);
+            let (response, status) = self.exchange(device, &command);
+            if chunks.peek().is_none() {
+                break (response, status);
+            }
+            assert_eq!(status, 0x9000);
+        };
+        while status >> 8 == 0x61 {
+            let (more, more_status) = self.exchange(device, &[0x00, GET_RESPONSE, 0x00, 0x00, status as u8]);
+            response.extend_from_slice(&more);
+            status = more_status;
+        }
+        (response, status)
+    }
+}
+
+#[test]
+fn chaining_and_get_response() {
+    with_device(|device| {
+        let mut reader = Reader::new();
+        let (_, status) = reader.transmit(device, [0x00, 0xA4, 0x04, 0x00], &AID);
+        assert_eq!(status, 0x9000);
+
+        // A reply longer than 256 bytes, collected with GET RESPONSE
+        let (random, status) = reader.transmit(device, [0x80, RNG, 0x02, 0x00], &[]);
+        assert_eq!(status, 0x9000);
+        assert_eq!(random.len(), 512);
+
+        // An update whose chunks exceed 255 bytes, sent by command chaining
+        let image: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let version = DEVICE_VERSION.number;
+        let begin = [(image.len() as u32).to_be_bytes(), version.to_be_bytes()].concat();
+        assert_eq!(reader.transmit(device, [0x80, UPDATE, BEGIN, 0x00], &begin).1, 0x9000);
+        for (index, chunk) in image.chunks(600).enumerate() {
+            let offset = (index * 600) as u32;
+            let write = [&offset.to_be_bytes()[..], chunk].concat();
+            let (next, status) = reader.transmit(device, [0x80, UPDATE, WRITE, 0x00], &write);
+            assert_eq!(status, 0x9000);
+            assert_eq!(next, (offset + chunk.len() as u32).to_be_bytes());
+        }
+
+        let signature = sign_image(version, &image);
+        let rebooted = rebooted(|| {
+            reader.transmit(device, [0x80, UPDATE, FINALIZE, 0x00], &signature);
+        });
+        assert_eq!(rebooted, Some(Rebooted::StagedUpdate));
+        assert_eq!(staged(), Some(image));
+    });
+}
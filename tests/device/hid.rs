@@ -0,0 +1,314 @@
+use admin_app::{Availability, Error, KeepaliveStatus, Transport};
+use apdu_dispatch::app::Interface;
+use apdu_dispatch::iso7816::Status;
+use crate::common::{
+    apdu, assert_signed, cancel, hid, hid_legacy, keepalives, rebooted, select, set_factory_resets,
+    with_attested_device, with_device, Device, Rebooted, CERTIFICATE, DEVICE_UUID, DEVICE_VERSION,
+};
+use ctaphid_dispatch::app as ctaphid;
+use ctaphid_dispatch::command::VendorCommand;
+
+const UPDATE: VendorCommand = VendorCommand::H51;
+const REBOOT: VendorCommand = VendorCommand::H53;
+const BOOT_SLOTS: VendorCommand = VendorCommand::H55;
+const CONFIRM: VendorCommand = VendorCommand::H56;
+const RESET: VendorCommand = VendorCommand::H59;
+const RNG: VendorCommand = VendorCommand::H60;
+const VERSION: VendorCommand = VendorCommand::H61;
+const UUID: VendorCommand = VendorCommand::H62;
+const RNG_HEALTH: VendorCommand = VendorCommand::H63;
+const ATTEST: VendorCommand = VendorCommand::H64;
+const CONFIG_GET: VendorCommand = VendorCommand::H65;
+const CONFIG_SET: VendorCommand = VendorCommand::H66;
+const PIN: VendorCommand = VendorCommand::H68;
+const READ_LOG: VendorCommand = VendorCommand::H6A;
+const SELF_TEST: VendorCommand = VendorCommand::H6C;
+const STORAGE_INFO: VendorCommand = VendorCommand::H6D;
+const LIST_APPS: VendorCommand = VendorCommand::H6E;
+const TRANSPORTS: VendorCommand = VendorCommand::H6F;
+const APP_ENABLEMENT: VendorCommand = VendorCommand::H70;
+
+#[test]
+fn version() {
+    with_device(|device| {
+        let version = hid_legacy(device, VERSION, &[]).unwrap();
+        assert_eq!(version, DEVICE_VERSION.number.to_be_bytes());
+    });
+}
+
+#[test]
+fn uuid() {
+    with_device(|device| {
+        assert_eq!(hid_legacy(device, UUID, &[]).unwrap(), DEVICE_UUID);
+    });
+}
+
+#[test]
+fn rng() {
+    with_device(|device| {
+        assert_eq!(hid_legacy(device, RNG, &[]).unwrap().len(), 57);
+        let first = hid(device, RNG, &[0x01, 0x00]).unwrap();
+        let second = hid(device, RNG, &[0x01, 0x00]).unwrap();
+        assert_eq!(first.len(), 256);
+        assert_ne!(first, second);
+        assert_eq!(hid(device, RNG, &[0x01]), Err(Error::Malformed));
+    });
+}
+
+#[test]
+fn rng_health() {
+    with_device(|device| {
+        let report = hid(device, RNG_HEALTH, &[]).unwrap();
+        assert_eq!(report.len(), 25);
+        assert_eq!(report[0], 0x00);
+        assert_eq!(report[1..5], 4096u32.to_be_bytes());
+        assert_eq!(report[9..13], [0x00; 4]);
+        assert_eq!(report[13..17], 8u32.to_be_bytes());
+        assert_eq!(report[21..], [0x00; 4]);
+
+        let report = hid(device, RNG_HEALTH, &[0x02, 0x00]).unwrap();
+        assert_eq!(report[1..5], 512u32.to_be_bytes());
+        assert_eq!(report[13..17], 1u32.to_be_bytes());
+    });
+}
+
+#[test]
+fn self_test() {
+    with_device(|device| {
+        // All four checks run and pass
+        assert_eq!(hid(device, SELF_TEST, &[]).unwrap(), [0x0F, 0x0F]);
+    });
+}
+
+#[test]
+fn attest() {
+    with_attested_device(|device, public_key| {
+        let nonce = [0xA5; 32];
+        let attestation = hid(device, ATTEST, &nonce).unwrap();
+        let length = usize::from(u16::from_be_bytes([attestation[0], attestation[1]]));
+        let (signature, certificate) = attestation[2..].split_at(length);
+        assert_eq!(certificate, CERTIFICATE);
+
+        let message = [&nonce[..], &DEVICE_UUID, &DEVICE_VERSION.number.to_be_bytes()].concat();
+        assert_signed(public_key, b"admin-app attest\0", &message, signature);
+
+        assert_eq!(hid(device, ATTEST, &[]), Err(Error::Malformed));
+    });
+    with_device(|device| {
+        assert_eq!(hid(device, ATTEST, &[0xA5; 32]), Err(Error::Unsupported));
+    });
+}
+
+#[test]
+fn storage_info() {
+    with_device(|device| {
+        let report = hid(device, STORAGE_INFO, &[]).unwrap();
+        // Internal filesystem: used and total bytes, wear
+        assert_eq!(report[..4], 0x8000u32.to_be_bytes());
+        assert_eq!(report[4..8], 0x1_0000u32.to_be_bytes());
+        assert_eq!(report[8], 3);
+        // External filesystem, unknown
+        assert_eq!(report[9..17], [0xFF; 8]);
+        assert_eq!(report[17], 0xFF);
+
+        let mut clients = Vec::new();
+        let mut records = &report[18..];
+        while let Some((&length, rest)) = records.split_first() {
+            let (name, rest) = rest.split_at(usize::from(length));
+            let (files, rest) = rest.split_at(4);
+            clients.push((name.to_vec(), u32::from_be_bytes(files.try_into().unwrap())));
+            records = rest;
+        }
+        assert_eq!(clients, [(b"admin".to_vec(), 2), (b"fido".to_vec(), 5)]);
+    });
+}
+
+#[test]
+fn list_apps() {
+    with_device(|device| {
+        let list = hid(device, LIST_APPS, &[]).unwrap();
+
+        // Per application: name, version, AID and CTAPHID commands
+        let mut fields = &list[1..];
+        let mut field = || {
+            let (length, rest) = fields.split_first().unwrap();
+            let (data, rest) = rest.split_at(usize::from(*length));
+            fields = rest;
+            data.to_vec()
+        };
+        let apps: Vec<[Vec<u8>; 4]> = (0..list[0]).map(|_| [field(), field(), field(), field()]).collect();
+        assert!(fields.is_empty());
+        assert_eq!(apps, [
+            [b"fido".to_vec(), b"1.0.0".to_vec(), vec![], vec![0x03, 0x10]],
+            [b"piv".to_vec(), b"0.2.1".to_vec(), vec![0xA0, 0x00, 0x00, 0x03, 0x08], vec![]],
+        ]);
+    });
+}
+
+#[test]
+fn reboot() {
+    with_device(|device| {
+        let rebooted = rebooted(|| { hid_legacy(device, REBOOT, &[]).ok(); });
+        assert_eq!(rebooted, Some(Rebooted::Normal));
+    });
+}
+
+#[test]
+fn update() {
+    with_device(|device| {
+        fn update(device: &mut Device, payload: &[u8]) -> Option<Rebooted> {
+            rebooted(|| {
+                hid_legacy(device, UPDATE, payload).ok();
+            })
+        }
+
+        assert_eq!(update(device, &[]), Some(Rebooted::FirmwareUpdate));
+        assert_eq!(update(device, &[0x01]), Some(Rebooted::FirmwareUpdateDestructive));
+
+        // With status byte
+        assert_eq!(update(device, &[0x02]), Some(Rebooted::FirmwareUpdate));
+        assert_eq!(update(device, &[0x03]), Some(Rebooted::FirmwareUpdateDestructive));
+        assert_eq!(hid(device, UPDATE, &[0x04]), Err(Error::Unsupported));
+    });
+}
+
+#[test]
+fn user_presence_keepalive() {
+    with_device(|device| {
+        hid_legacy(device, VERSION, &[]).unwrap();
+        assert!(keepalives().is_empty());
+        let rebooted = rebooted(|| { hid_legacy(device, UPDATE, &[]).ok(); });
+        assert_eq!(rebooted, Some(Rebooted::FirmwareUpdate));
+        assert_eq!(keepalives(), [KeepaliveStatus::UserPresenceNeeded, KeepaliveStatus::Processing]);
+    });
+}
+
+#[test]
+fn user_presence_cancelled() {
+    with_device(|device| {
+        cancel();
+        let rebooted = rebooted(|| {
+            assert_eq!(hid(device, PIN, &[0x01, b'1', b'2', b'3', b'4']), Err(Error::Cancelled));
+            assert_eq!(hid(device, UPDATE, &[0x02]), Err(Error::Cancelled));
+            // Old hosts get the reply of the original firmware
+            assert_eq!(hid_legacy(device, UPDATE, &[]), Err(ctaphid::Error::InvalidLength));
+        });
+        assert_eq!(rebooted, None);
+    });
+}
+
+#[test]
+fn factory_reset() {
+    with_device(|device| {
+        hid(device, CONFIG_SET, &[0x02, 0x00, 0x00, 0x01, 0xF4]).unwrap();
+        hid(device, PIN, &[0x01, b'1', b'2', b'3', b'4']).unwrap();
+        let rebooted = rebooted(|| { hid(device, RESET, &[]).ok(); });
+        assert_eq!(rebooted, Some(Rebooted::FactoryReset));
+
+        // The platform reboots, the admin app has removed its own files
+        assert_eq!(hid(device, CONFIG_GET, &[0x02]).unwrap(), 10_000u32.to_be_bytes());
+        assert_eq!(hid(device, PIN, &[0x04]).unwrap(), [0x00, 0x08, 0x00]);
+    });
+}
+
+#[test]
+fn factory_reset_audited() {
+    with_device(|device| {
+        // First boot after the platform wiped the storage
+        set_factory_resets(1);
+        let log = hid(device, READ_LOG, &[]).unwrap();
+        assert_eq!(log[32..], [0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(hid(device, READ_LOG, &[]).unwrap(), log);
+    });
+}
+
+#[test]
+fn confirm() {
+    with_device(|device| {
+        assert_eq!(hid(device, BOOT_SLOTS, &[]).unwrap(), [0x00, 0xFF, 0x00]);
+        hid(device, CONFIRM, &[]).unwrap();
+        assert_eq!(hid(device, BOOT_SLOTS, &[]).unwrap(), [0x00, 0xFF, 0x01]);
+    });
+}
+
+#[test]
+fn config() {
+    with_device(|device| {
+        assert_eq!(hid(device, CONFIG_GET, &[0x02]).unwrap(), 10_000u32.to_be_bytes());
+        hid(device, CONFIG_SET, &[0x02, 0x00, 0x00, 0x01, 0xF4]).unwrap();
+        assert_eq!(hid(device, CONFIG_GET, &[0x02]).unwrap(), 500u32.to_be_bytes());
+        assert_eq!(hid(device, CONFIG_SET, &[0x02, 0x00]), Err(Error::Invalid));
+        assert_eq!(hid(device, CONFIG_GET, &[0x7F]), Err(Error::Unsupported));
+    });
+}
+
+#[test]
+fn wrong_pin() {
+    with_device(|device| {
+        assert_eq!(hid(device, PIN, &[0x03, b'1', b'2', b'3', b'4']), Err(Error::Invalid));
+        hid(device, PIN, &[0x01, b'1', b'2', b'3', b'4']).unwrap();
+        assert_eq!(hid(device, PIN, &[0x03, b'0', b'0', b'0', b'0']), Err(Error::WrongPin(7)));
+        // Too short, the retries are kept
+        assert_eq!(hid(device, PIN, &[0x03]), Err(Error::Malformed));
+        assert_eq!(hid(device, PIN, &[0x04]).unwrap(), [0x01, 0x07, 0x00]);
+        hid(device, PIN, &[0x03, b'1', b'2', b'3', b'4']).unwrap();
+    });
+}
+
+#[test]
+fn pin_session_per_transport() {
+    with_device(|device| {
+        let wink_duration = [0x02, 0x00, 0x00, 0x01, 0xF4];
+        hid(device, PIN, &[0x01, b'1', b'2', b'3', b'4']).unwrap();
+        hid(device, CONFIG_SET, &wink_duration).unwrap();
+
+        // The APDU session is separate, and ending it keeps the CTAPHID session
+        assert_eq!(apdu(device, Interface::Contact, &[0x00, 0x62, 0x00, 0x00]), Err(Status::SecurityStatusNotSatisfied));
+        select(device);
+        hid(device, CONFIG_SET, &wink_duration).unwrap();
+        assert_eq!(hid(device, PIN, &[0x04]).unwrap(), [0x01, 0x08, 0x01]);
+
+        hid(device, PIN, &[0x05]).unwrap();
+        assert_eq!(hid(device, CONFIG_SET, &wink_duration), Err(Error::Unauthorized));
+        assert_eq!(hid(device, PIN, &[0x04]).unwrap(), [0x01, 0x08, 0x00]);
+    });
+}
+
+#[test]
+fn transports() {
+    with_device(|device| {
+        assert_eq!(hid(device, TRANSPORTS, &[]).unwrap(), [0x07]);
+        // At least one USB transport stays enabled
+        assert_eq!(hid(device, TRANSPORTS, &[0x04]), Err(Error::Invalid));
+        hid(device, TRANSPORTS, &[0x02]).unwrap();
+        assert_eq!(hid(device, TRANSPORTS, &[]).unwrap(), [0x02]);
+        assert!(device.transport_enabled(Transport::UsbCtaphid));
+        assert!(!device.transport_enabled(Transport::Nfc));
+    });
+}
+
+#[test]
+fn app_enablement() {
+    with_device(|device| {
+        let aid = [0xA0, 0x00, 0x00, 0x05, 0x27];
+        assert!(hid(device, APP_ENABLEMENT, &[]).unwrap().is_empty());
+        hid(device, APP_ENABLEMENT, &[&[0x00][..], &aid].concat()).unwrap();
+        assert_eq!(hid(device, APP_ENABLEMENT, &[]).unwrap(), [&[0x05][..], &aid].concat());
+        assert!(!device.app_enabled(&aid));
+
+        // The admin app cannot be disabled
+        let admin = [0xA0, 0x00, 0x00, 0x08, 0x47, 0x00, 0x00, 0x00, 0x01];
+        assert_eq!(hid(device, APP_ENABLEMENT, &[&[0x00][..], &admin].concat()), Err(Error::Invalid));
+
+        hid(device, APP_ENABLEMENT, &[&[0x01][..], &aid].concat()).unwrap();
+        assert!(hid(device, APP_ENABLEMENT, &[]).unwrap().is_empty());
+        assert!(device.app_enabled(&aid));
+    });
+}
+
+#[test]
+fn unknown_command() {
+    with_device(|device| {
+        assert_eq!(hid(device, VendorCommand::H7F, &[]), Err(Error::Unsupported));
+    });
+}
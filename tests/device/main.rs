@@ -0,0 +1,9 @@
+//! The admin app on a simulated device, driven over CTAPHID and APDU.
+mod common;
+
+mod apdu;
+mod audit;
+mod channel;
+mod dispatch;
+mod hid;
+mod update;
@@ -0,0 +1,142 @@
+use admin_app::Error;
+use crate::common::{hid, rebooted, sign_image, staged, with_device, Device, Rebooted, DEVICE_VERSION};
+use ctaphid_dispatch::command::VendorCommand;
+
+const UPDATE: VendorCommand = VendorCommand::H51;
+const MIN_VERSION: VendorCommand = VendorCommand::H54;
+const UPDATE_STATUS: VendorCommand = VendorCommand::H58;
+
+const BEGIN: u8 = 0x10;
+const WRITE: u8 = 0x11;
+const FINALIZE: u8 = 0x12;
+
+// Update states and failure reasons, as per UPDATE_STATUS.
+const RECEIVING: u8 = 1;
+const STAGED: u8 = 3;
+const FAILED: u8 = 4;
+const SIGNATURE: u8 = 5;
+const VERSION: u8 = 6;
+
+fn image() -> Vec<u8> {
+    (0..1300).map(|i| i as u8).collect()
+}
+
+fn begin(device: &mut Device, length: usize, version: u32) -> Result<Vec<u8>, Error> {
+    let mut payload = vec![BEGIN];
+    payload.extend_from_slice(&(length as u32).to_be_bytes());
+    payload.extend_from_slice(&version.to_be_bytes());
+    hid(device, UPDATE, &payload)
+}
+
+fn write(device: &mut Device, offset: usize, chunk: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut payload = vec![WRITE];
+    payload.extend_from_slice(&(offset as u32).to_be_bytes());
+    payload.extend_from_slice(chunk);
+    hid(device, UPDATE, &payload)
+}
+
+fn finalize(device: &mut Device, signature: &[u8; 64]) -> Result<Vec<u8>, Error> {
+    let mut payload = vec![FINALIZE];
+    payload.extend_from_slice(signature);
+    hid(device, UPDATE, &payload)
+}
+
+/// Begins an upload of `image` and writes it in chunks.
+fn upload(device: &mut Device, version: u32, image: &[u8]) {
+    begin(device, image.len(), version).unwrap();
+    for (index, chunk) in image.chunks(512).enumerate() {
+        let offset = index * 512;
+        let next = write(device, offset, chunk).unwrap();
+        assert_eq!(next, ((offset + chunk.len()) as u32).to_be_bytes());
+    }
+}
+
+/// State and failure reason of the update status.
+fn status(device: &mut Device) -> [u8; 2] {
+    let status = hid(device, UPDATE_STATUS, &[]).unwrap();
+    [status[0], status[1]]
+}
+
+#[test]
+fn staged_image() {
+    with_device(|device| {
+        let image = image();
+        let version = DEVICE_VERSION.number + 1;
+        upload(device, version, &image);
+        assert_eq!(status(device), [RECEIVING, 0]);
+
+        let signature = sign_image(version, &image);
+        let rebooted = rebooted(|| { finalize(device, &signature).ok(); });
+        assert_eq!(rebooted, Some(Rebooted::StagedUpdate));
+        assert_eq!(staged(), Some(image.clone()));
+
+        let status = hid(device, UPDATE_STATUS, &[]).unwrap();
+        assert_eq!(status[..2], [STAGED, 0]);
+        assert_eq!(status[2..6], (image.len() as u32).to_be_bytes());
+        assert_eq!(status[6..], (image.len() as u32).to_be_bytes());
+    });
+}
+
+#[test]
+fn bad_signature() {
+    with_device(|device| {
+        let image = image();
+        let version = DEVICE_VERSION.number;
+        upload(device, version, &image);
+
+        // Signed for another version
+        let signature = sign_image(version + 1, &image);
+        let rebooted = rebooted(|| {
+            assert_eq!(finalize(device, &signature), Err(Error::Signature));
+        });
+        assert_eq!(rebooted, None);
+        assert_eq!(status(device), [FAILED, SIGNATURE]);
+    });
+}
+
+#[test]
+fn out_of_sequence() {
+    with_device(|device| {
+        let image = image();
+        let signature = sign_image(DEVICE_VERSION.number, &image);
+        assert_eq!(write(device, 0, &image[..512]), Err(Error::Sequence));
+        assert_eq!(finalize(device, &signature), Err(Error::Sequence));
+
+        begin(device, image.len(), DEVICE_VERSION.number).unwrap();
+        assert_eq!(write(device, 512, &image[512..1024]), Err(Error::Sequence));
+        write(device, 0, &image[..512]).unwrap();
+        assert_eq!(write(device, 0, &image[..512]), Err(Error::Sequence));
+        assert_eq!(write(device, 512, &image[512..]), Ok(1300u32.to_be_bytes().to_vec()));
+        assert_eq!(write(device, 1300, &[0x00]), Err(Error::Sequence));
+        assert_eq!(status(device), [RECEIVING, 0]);
+    });
+}
+
+#[test]
+fn incomplete() {
+    with_device(|device| {
+        let image = image();
+        let signature = sign_image(DEVICE_VERSION.number, &image);
+        begin(device, image.len(), DEVICE_VERSION.number).unwrap();
+        write(device, 0, &image[..512]).unwrap();
+        assert_eq!(finalize(device, &signature), Err(Error::Sequence));
+        assert_eq!(staged().map(|staged| staged.len()), Some(512));
+    });
+}
+
+#[test]
+fn rollback() {
+    with_device(|device| {
+        let running = DEVICE_VERSION.number;
+        assert_eq!(begin(device, 1300, running - 1), Err(Error::Invalid));
+        assert_eq!(status(device), [FAILED, VERSION]);
+        begin(device, 1300, running).unwrap();
+
+        // Neither below the minimum version, which cannot be lowered
+        assert_eq!(hid(device, MIN_VERSION, &running.to_be_bytes()).unwrap(), running.to_be_bytes());
+        assert_eq!(begin(device, 1300, running - 1), Err(Error::Invalid));
+        assert_eq!(status(device), [FAILED, VERSION]);
+        begin(device, 1300, running).unwrap();
+        assert_eq!(hid(device, MIN_VERSION, &(running - 1).to_be_bytes()), Err(Error::Invalid));
+    });
+}